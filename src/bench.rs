@@ -0,0 +1,305 @@
+
+use std::io::{self, ErrorKind, Write};
+use std::net::IpAddr;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mio::{Events, Poll};
+
+use crate::buffer::{BufferPool, PacketBuffer};
+use crate::configfile::{Application, ConfigFile};
+use crate::flow::FlowTable;
+use crate::packet::{same_family, IpPacket};
+use crate::server::{prepare, register_tun, wait_for_tun};
+
+const SYN: u8 = 0x02;
+const ACK: u8 = 0x10;
+const PSH_ACK: u8 = 0x18;
+const SYN_ACK: u8 = 0x12;
+
+/// Data segments sent each way on every flow after the ClientHello.
+const DATA_SEGMENTS: usize = 8;
+const DATA_LEN: usize = 1000;
+
+/// Packets sent to measure how fast an idle reader wakes up, and how idle it is before each.
+const WAKEUPS: usize = 50;
+const IDLE: Duration = Duration::from_millis(20);
+
+#[cfg(feature = "alloc-stats")]
+mod counting {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    pub static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+    struct Counting;
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static GLOBAL: Counting = Counting;
+}
+
+/// Number of heap allocations so far, only counted with the `alloc-stats` feature.
+fn allocations() -> Option<u64> {
+    #[cfg(feature = "alloc-stats")]
+    {
+        Some(counting::ALLOCATIONS.load(std::sync::atomic::Ordering::Relaxed))
+    }
+    #[cfg(not(feature = "alloc-stats"))]
+    {
+        None
+    }
+}
+
+/// Stands in for the tun, counting what would have been written to it.
+#[derive(Default)]
+struct Discard {
+    packets: u64,
+}
+
+impl Write for Discard {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.packets += 1;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The addresses a flow of an application is replayed with.
+struct Endpoints {
+    origin: IpAddr,
+    phony: IpAddr,
+    dest: IpAddr,
+    port: u16,
+}
+
+fn endpoints(app: &Application) -> Option<Endpoints> {
+    app.mappings.iter().find_map(|mapping| {
+        let dest = mapping.dest.nth(0);
+        let origin = same_family(&dest, app.origin, app.origin6)?;
+        let port = match &app.ports {
+            Some(ranges) => ranges.first()?.start,
+            None => 443,
+        };
+        Some(Endpoints { origin, phony: mapping.phony.nth(0), dest, port })
+    })
+}
+
+/// A tcp segment with a 20 byte header, as it would be read from the tun.
+#[allow(clippy::too_many_arguments)]
+pub fn tcp_packet(source: IpAddr, destination: IpAddr, source_port: u16, destination_port: u16,
+              flags: u8, seq: u32, ack: u32, payload: &[u8]) -> Vec<u8> {
+    let mut data = match (source, destination) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            let mut header = vec![0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, 6, 0, 0];
+            header.extend_from_slice(&source.octets());
+            header.extend_from_slice(&destination.octets());
+            header
+        },
+        (IpAddr::V6(source), IpAddr::V6(destination)) => {
+            let mut header = vec![0x60, 0, 0, 0, 0, 0, 6, 64];
+            header.extend_from_slice(&source.octets());
+            header.extend_from_slice(&destination.octets());
+            header
+        },
+        _ => unreachable!(),
+    };
+    let header_len = data.len();
+    data.extend_from_slice(&source_port.to_be_bytes());
+    data.extend_from_slice(&destination_port.to_be_bytes());
+    data.extend_from_slice(&seq.to_be_bytes());
+    data.extend_from_slice(&ack.to_be_bytes());
+    data.extend_from_slice(&[5 << 4, flags, 0xff, 0xff, 0, 0, 0, 0]);
+    data.extend_from_slice(payload);
+
+    if source.is_ipv6() {
+        let len = (data.len() - header_len) as u16;
+        data[4..6].copy_from_slice(&len.to_be_bytes());
+    } else {
+        let len = data.len() as u16;
+        data[2..4].copy_from_slice(&len.to_be_bytes());
+    }
+    let mut packet = IpPacket::new(PacketBuffer::from(data)).unwrap();
+    packet.fix_checksums();
+    packet.into_bytes()
+}
+
+/// A ClientHello carrying only a server_name extension.
+pub fn client_hello(hostname: &str) -> Vec<u8> {
+    let name = hostname.as_bytes();
+    let mut extension = vec![0, 0];
+    extension.extend_from_slice(&(name.len() as u16 + 5).to_be_bytes());
+    extension.extend_from_slice(&(name.len() as u16 + 3).to_be_bytes());
+    extension.push(0);
+    extension.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    extension.extend_from_slice(name);
+
+    // client version, random, empty session id, one cipher suite, no compression
+    let mut body = vec![0x03, 0x03];
+    body.extend_from_slice(&[0x42; 32]);
+    body.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+    body.extend_from_slice(&(extension.len() as u16).to_be_bytes());
+    body.extend_from_slice(&extension);
+
+    let mut handshake = vec![0x01];
+    handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    handshake.extend_from_slice(&body);
+
+    let mut record = vec![0x16, 0x03, 0x01];
+    record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+    record.extend_from_slice(&handshake);
+    record
+}
+
+/// The packets of a connection as they come out of the tun: the handshake, the ClientHello
+/// and some data both ways.
+fn connection(ends: &Endpoints, local_port: u16) -> Vec<Vec<u8>> {
+    let hello = client_hello("example.com");
+    let data = [0x17; DATA_LEN];
+    let (mut seq, mut ack) = (1000u32, 5000u32);
+    let mut packets = vec![
+        tcp_packet(ends.origin, ends.phony, local_port, ends.port, SYN, seq, 0, &[]),
+        tcp_packet(ends.dest, ends.phony, ends.port, local_port, SYN_ACK, ack, seq + 1, &[]),
+    ];
+    seq += 1;
+    ack += 1;
+    packets.push(tcp_packet(ends.origin, ends.phony, local_port, ends.port, ACK, seq, ack, &[]));
+    packets.push(tcp_packet(ends.origin, ends.phony, local_port, ends.port, PSH_ACK, seq, ack, &hello));
+    seq += hello.len() as u32;
+    for _ in 0..DATA_SEGMENTS {
+        packets.push(tcp_packet(ends.origin, ends.phony, local_port, ends.port, PSH_ACK, seq, ack, &data));
+        seq += DATA_LEN as u32;
+    }
+    for _ in 0..DATA_SEGMENTS {
+        packets.push(tcp_packet(ends.dest, ends.phony, ends.port, local_port, PSH_ACK, ack, seq, &data));
+        ack += DATA_LEN as u32;
+    }
+    packets
+}
+
+/// Pushes `packets` through the same steps as `serve_forever`, reading each one into a
+/// buffer of `buffers` and running its job right away.
+fn replay(config: &ConfigFile, packets: &[Vec<u8>], count: usize, buffers: &BufferPool) -> (Duration, Option<u64>, u64) {
+    let flows = Arc::new(Mutex::new(FlowTable::new(Duration::from_secs(config.flow_timeout), Duration::from_secs(config.translated_flow_timeout))));
+    let dev = Arc::new(Mutex::new(Discard::default()));
+
+    let allocated = allocations();
+    let start = Instant::now();
+    for template in packets.iter().cycle().take(count) {
+        // the copy the kernel makes when the tun is read
+        let mut buffer = buffers.get();
+        buffer[..template.len()].copy_from_slice(template);
+        buffer.truncate(template.len());
+        if let Some((_, _, job)) = prepare(config, &flows, &dev, buffer) {
+            job();
+        }
+    }
+    let elapsed = start.elapsed();
+    let allocated = allocations().zip(allocated).map(|(after, before)| after - before);
+
+    let written = dev.lock().unwrap().packets;
+    (elapsed, allocated, written)
+}
+
+/// Measures how long a packet takes from being written to being read by a reader that was
+/// idle, waiting the way `serve_forever` waits for the tun. A datagram socket stands in for
+/// the tun, returns the latencies sorted.
+fn wakeup_latency() -> Result<Vec<Duration>, String> {
+    let (writer, reader) = UnixDatagram::pair().map_err(|e| format!("could not create a socket pair: {}", e))?;
+    reader.set_nonblocking(true).map_err(|e| format!("could not make the socket non-blocking: {}", e))?;
+    let mut poll = Poll::new().map_err(|e| format!("could not create a poll: {}", e))?;
+    register_tun(&poll, reader.as_raw_fd())?;
+
+    let (sent_tx, sent_rx) = mpsc::channel();
+    let sender = thread::spawn(move || {
+        for _ in 0..WAKEUPS {
+            thread::sleep(IDLE);
+            let _ = sent_tx.send(Instant::now());
+            if writer.send(&[0x45; 60]).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut events = Events::with_capacity(8);
+    let mut buffer = [0u8; 64];
+    let mut latencies = Vec::with_capacity(WAKEUPS);
+    while latencies.len() < WAKEUPS {
+        match reader.recv(&mut buffer) {
+            Ok(_) => {
+                let sent: Instant = sent_rx.recv().map_err(|_| "the sender stopped early".to_string())?;
+                latencies.push(sent.elapsed());
+            },
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                wait_for_tun(&mut poll, &mut events, Duration::from_secs(1))?;
+            },
+            Err(e) => { return Err(format!("could not read the socket: {}", e)); }
+        }
+    }
+    let _ = sender.join();
+    latencies.sort();
+    Ok(latencies)
+}
+
+/// Replays synthetic connections to the first destination of every application, once with
+/// a fresh buffer for each packet and once with pooled buffers, then measures the wakeup
+/// latency of an idle reader.
+pub fn run_bench(config: &ConfigFile, count: usize) -> Result<(), String> {
+    let ends: Vec<Endpoints> = config.applications.iter().filter_map(|app| endpoints(app)).collect();
+    if ends.is_empty() {
+        return Err("no application has an address in dest to replay packets to, domain names aren't resolved by bench".to_string());
+    }
+
+    // enough connections that the flow table doesn't stay tiny
+    let mut packets = Vec::new();
+    for local_port in 40000..40000 + (count / ends.len() / (4 + 2 * DATA_SEGMENTS)).clamp(1, 20000) as u16 {
+        for ends in ends.iter() {
+            packets.extend(connection(ends, local_port));
+        }
+    }
+
+    let size = (config.mtu + 4) as usize;
+    if let Some(packet) = packets.iter().find(|packet| packet.len() > size) {
+        return Err(format!("mtu {} is too small for the {} byte packets of the bench", config.mtu, packet.len()));
+    }
+
+    println!("replaying {} packets of {} connections to {} applications", count, packets.len() / (4 + 2 * DATA_SEGMENTS), ends.len());
+    for (name, keep) in [("fresh buffers", 0), ("pooled buffers", 128)] {
+        let (elapsed, allocated, written) = replay(config, &packets, count, &BufferPool::new(size, keep));
+        let rate = count as f64 / elapsed.as_secs_f64();
+        print!("{}: {} packets in {:.1?} ({:.0} packets/s), {} written", name, count, elapsed, rate, written);
+        match allocated {
+            Some(allocated) => println!(", {:.2} allocations per packet", allocated as f64 / count as f64),
+            None => println!(),
+        }
+    }
+    if allocations().is_none() {
+        println!("build with --features alloc-stats to count the allocations");
+    }
+
+    let latencies = wakeup_latency()?;
+    println!("first packet after {:?} idle: read after {:.1?} (median), {:.1?} (min), {:.1?} (max) over {} packets",
+             IDLE, latencies[latencies.len() / 2], latencies[0], latencies[latencies.len() - 1], latencies.len());
+    Ok(())
+}
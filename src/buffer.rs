@@ -0,0 +1,96 @@
+
+use std::fmt;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+/// Buffers for the packets read from the tun. A buffer goes back to its pool when it's
+/// dropped, after the worker is done with the packet, and the next read lands in it again
+/// instead of in a fresh allocation.
+#[derive(Clone)]
+pub struct BufferPool {
+    free: Arc<Mutex<Vec<Vec<u8>>>>,
+    /// size of the buffers, enough for a packet of the mtu
+    size: usize,
+    /// how many free buffers are kept around at most, the rest are freed
+    keep: usize,
+}
+
+impl BufferPool {
+    pub fn new(size: usize, keep: usize) -> BufferPool {
+        BufferPool { free: Arc::new(Mutex::new(Vec::with_capacity(keep))), size, keep }
+    }
+
+    /// A buffer of the pool's size, a recycled one when there is one.
+    pub fn get(&self) -> PacketBuffer {
+        let mut data = self.free.lock().unwrap().pop().unwrap_or_default();
+        data.resize(self.size, 0);
+        PacketBuffer { data, pool: Some(self.clone()) }
+    }
+
+    fn give_back(&self, data: Vec<u8>) {
+        let mut free = self.free.lock().unwrap();
+        if free.len() < self.keep && data.capacity() >= self.size {
+            free.push(data);
+        }
+    }
+}
+
+/// A packet, in a buffer of a `BufferPool` or in one of its own.
+pub struct PacketBuffer {
+    data: Vec<u8>,
+    pool: Option<BufferPool>,
+}
+
+impl PacketBuffer {
+    /// Cuts the buffer down to the `len` bytes a read filled in.
+    pub fn truncate(&mut self, len: usize) {
+        self.data.truncate(len);
+    }
+
+    /// Takes the bytes out, the buffer doesn't go back to its pool.
+    pub fn into_vec(mut self) -> Vec<u8> {
+        mem::take(&mut self.data)
+    }
+}
+
+impl From<Vec<u8>> for PacketBuffer {
+    fn from(data: Vec<u8>) -> PacketBuffer {
+        PacketBuffer { data, pool: None }
+    }
+}
+
+impl Clone for PacketBuffer {
+    /// A copy in a buffer of its own.
+    fn clone(&self) -> PacketBuffer {
+        PacketBuffer::from(self.data.clone())
+    }
+}
+
+impl fmt::Debug for PacketBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PacketBuffer({} bytes)", self.data.len())
+    }
+}
+
+impl Deref for PacketBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl DerefMut for PacketBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+}
+
+impl Drop for PacketBuffer {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            pool.give_back(mem::take(&mut self.data));
+        }
+    }
+}
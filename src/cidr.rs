@@ -0,0 +1,246 @@
+
+use std::fmt;
+use std::marker::PhantomData;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::{self, SeqAccess, Visitor};
+
+/// An IPv4 or IPv6 prefix, written as `104.16.0.0/13` or `2606:4700::/32`, or as a plain
+/// address for a single host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+/// An address as a number, IPv4 addresses take up the low 32 bits.
+pub fn addr_bits(addr: IpAddr) -> u128 {
+    match addr {
+        IpAddr::V4(addr) => u32::from(addr) as u128,
+        IpAddr::V6(addr) => u128::from(addr),
+    }
+}
+
+fn bits_addr(bits: u128, ipv6: bool) -> IpAddr {
+    if ipv6 {
+        IpAddr::V6(Ipv6Addr::from(bits))
+    } else {
+        IpAddr::V4(Ipv4Addr::from(bits as u32))
+    }
+}
+
+/// Number of bits in the addresses of the family of `addr`.
+pub fn addr_width(addr: IpAddr) -> u8 {
+    if addr.is_ipv6() { 128 } else { 32 }
+}
+
+impl Cidr {
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Cidr, String> {
+        if prefix > addr_width(addr) {
+            return Err(format!("invalid prefix length /{}", prefix));
+        }
+        let cidr = Cidr { addr, prefix };
+        if addr_bits(addr) & cidr.hostmask() != 0 {
+            return Err(format!("{} has host bits set, did you mean {}/{}?", cidr, cidr.network(), prefix));
+        }
+        Ok(cidr)
+    }
+
+    /// A prefix of `size` addresses of the family of `addr` starting at the address `start`.
+    pub fn from_bits(start: u128, size_bits: u8, ipv6: bool) -> Result<Cidr, String> {
+        let width = if ipv6 { 128 } else { 32 };
+        Cidr::new(bits_addr(start, ipv6), width - size_bits)
+    }
+
+    pub fn host(addr: IpAddr) -> Cidr {
+        Cidr { addr, prefix: addr_width(addr) }
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        self.addr.is_ipv6()
+    }
+
+    /// Number of bits of the addresses that aren't part of the prefix.
+    pub fn host_bits(&self) -> u8 {
+        addr_width(self.addr) - self.prefix
+    }
+
+    pub fn hostmask(&self) -> u128 {
+        u128::MAX.checked_shr(128 - self.host_bits() as u32).unwrap_or(0)
+    }
+
+    pub fn network(&self) -> IpAddr {
+        bits_addr(addr_bits(self.addr) & !self.hostmask(), self.is_ipv6())
+    }
+
+    /// Number of addresses in the prefix, saturated for the very large IPv6 prefixes.
+    pub fn size(&self) -> u128 {
+        1u128.checked_shl(self.host_bits() as u32).unwrap_or(u128::MAX)
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        addr.is_ipv6() == self.is_ipv6() && addr_bits(addr) & !self.hostmask() == addr_bits(self.addr)
+    }
+
+    /// The prefix of length `prefix` that `addr` is in.
+    pub fn containing(addr: IpAddr, prefix: u8) -> Result<Cidr, String> {
+        if prefix > addr_width(addr) {
+            return Err(format!("invalid prefix length /{}", prefix));
+        }
+        Ok(Cidr { addr: Cidr { addr, prefix }.network(), prefix })
+    }
+
+    /// The prefix of a network given as an address and a netmask like `255.255.255.0`.
+    pub fn from_netmask(addr: Ipv4Addr, netmask: Ipv4Addr) -> Result<Cidr, String> {
+        let mask = u32::from(netmask);
+        if mask.leading_ones() + mask.trailing_zeros() != 32 {
+            return Err(format!("{} is not a valid netmask", netmask));
+        }
+        Cidr::new(IpAddr::V4(Ipv4Addr::from(u32::from(addr) & mask)), mask.leading_ones() as u8)
+    }
+
+    /// true if every address of `other` is inside the prefix
+    pub fn covers(&self, other: &Cidr) -> bool {
+        self.prefix <= other.prefix && self.contains(other.addr)
+    }
+
+    /// true if the two prefixes have any address in common
+    pub fn overlaps(&self, other: &Cidr) -> bool {
+        self.covers(other) || other.covers(self)
+    }
+
+    /// Position of `addr` inside the prefix, `None` if it's outside.
+    pub fn offset_of(&self, addr: IpAddr) -> Option<u128> {
+        if self.contains(addr) {
+            Some(addr_bits(addr) & self.hostmask())
+        } else {
+            None
+        }
+    }
+
+    /// The address at `offset` inside the prefix.
+    pub fn nth(&self, offset: u128) -> IpAddr {
+        bits_addr(addr_bits(self.addr) | (offset & self.hostmask()), self.is_ipv6())
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.prefix == addr_width(self.addr) {
+            write!(f, "{}", self.addr)
+        } else {
+            write!(f, "{}/{}", self.addr, self.prefix)
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix.parse::<u8>().map_err(|_| format!("invalid prefix in \"{}\"", s))?)),
+            None => (s, None),
+        };
+        let addr = addr.parse::<IpAddr>().map_err(|_| format!("invalid address \"{}\"", s))?;
+        Cidr::new(addr, prefix.unwrap_or(addr_width(addr)))
+    }
+}
+
+impl Serialize for Cidr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Cidr, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+struct OneOrManyVisitor<T>(PhantomData<T>);
+
+impl<'de, T> Visitor<'de> for OneOrManyVisitor<T>
+where
+    T: FromStr<Err = String> + Deserialize<'de>,
+{
+    type Value = Vec<T>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a single entry or a list of them")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Vec<T>, E> {
+        Ok(vec![v.parse().map_err(E::custom)?])
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<T>, A::Error> {
+        let mut list = Vec::new();
+        while let Some(entry) = seq.next_element()? {
+            list.push(entry);
+        }
+        Ok(list)
+    }
+}
+
+/// Deserializes either a single entry written as a string or a list of entries.
+pub fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr<Err = String> + Deserialize<'de>,
+{
+    deserializer.deserialize_any(OneOrManyVisitor(PhantomData))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offsets_round_trip() {
+        let v4: Cidr = "10.1.2.0/24".parse().unwrap();
+        assert_eq!(v4.offset_of("10.1.2.77".parse().unwrap()), Some(77));
+        assert_eq!(v4.nth(77), "10.1.2.77".parse::<IpAddr>().unwrap());
+
+        let v6: Cidr = "2001:db8::/64".parse().unwrap();
+        let addr: IpAddr = "2001:db8::1:0:5".parse().unwrap();
+        let offset = v6.offset_of(addr).unwrap();
+        assert_eq!(offset, (1 << 32) + 5);
+        assert_eq!(v6.nth(offset), addr);
+    }
+
+    #[test]
+    fn offsets_outside() {
+        let v4: Cidr = "10.1.2.0/24".parse().unwrap();
+        assert_eq!(v4.offset_of("10.1.3.1".parse().unwrap()), None);
+        assert_eq!(v4.offset_of("::ffff:10.1.2.1".parse().unwrap()), None);
+        // offsets past the end wrap around inside the prefix
+        assert_eq!(v4.nth(256 + 3), "10.1.2.3".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn single_hosts_and_whole_spaces() {
+        let host = Cidr::host("192.0.2.7".parse().unwrap());
+        assert_eq!(host.size(), 1);
+        assert_eq!(host.offset_of("192.0.2.7".parse().unwrap()), Some(0));
+        assert_eq!(host.nth(5), "192.0.2.7".parse::<IpAddr>().unwrap());
+
+        let all: Cidr = "::/0".parse().unwrap();
+        assert_eq!(all.size(), u128::MAX);
+        assert_eq!(all.offset_of("::2".parse().unwrap()), Some(2));
+    }
+
+    #[test]
+    fn parsing() {
+        assert!("10.1.2.1/24".parse::<Cidr>().is_err());
+        assert!("10.1.2.0/33".parse::<Cidr>().is_err());
+        assert_eq!(Cidr::containing("10.1.2.1".parse().unwrap(), 24).unwrap(), "10.1.2.0/24".parse().unwrap());
+        assert_eq!(Cidr::from_netmask("10.1.2.1".parse().unwrap(), "255.255.255.0".parse().unwrap()).unwrap(),
+                   "10.1.2.0/24".parse().unwrap());
+        assert!(Cidr::from_netmask("10.1.2.1".parse().unwrap(), "255.0.255.0".parse().unwrap()).is_err());
+    }
+}
@@ -5,6 +5,9 @@ pub fn parse_args(args: Vec<String>, progname: String) -> Result<Matches, String
     let mut opts = Options::new();
 
     opts.opt("c", "config", "path to the configuration file", "config", HasArg::Yes, Occur::Optional);
+    opts.opt("f", "format", "format of the configuration file (json, yaml, toml, ini, ron or json5), by default guessed from its extension", "format", HasArg::Yes, Occur::Optional);
+    opts.opt("s", "set", "overrides a field of the configuration file, like origin=1.2.3.4 or applications.test0.origin=1.2.3.5, can be repeated", "key=value", HasArg::Yes, Occur::Multi);
+    opts.opt("n", "packets", "number of packets the bench command replays, 100000 by default", "packets", HasArg::Yes, Occur::Optional);
     opts.opt("h", "help", "prints this help message", "help", HasArg::No, Occur::Optional);
 
     let matches = match opts.parse(&args[1..]){
@@ -14,7 +17,7 @@ pub fn parse_args(args: Vec<String>, progname: String) -> Result<Matches, String
         }
     };
 
-    if matches.free.len() == 0 {
+    if matches.free.is_empty() {
         return Err("Command not specified".to_string());
     }
 
@@ -23,11 +26,11 @@ pub fn parse_args(args: Vec<String>, progname: String) -> Result<Matches, String
         exit(0);
     }
 
-    return Ok(matches);
+    Ok(matches)
 }
 
 fn print_usage(progname: String, opts: Options){
     let brief = format!("Usage: {} COMMAND [OPTIONS]", progname);
     let usage = opts.usage(&brief);
-    println!("{}\nCOMMAND=\trun\n", usage);
+    println!("{}\nCOMMAND=\trun | plan | check | bench\n", usage);
 }
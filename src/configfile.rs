@@ -1,71 +1,1108 @@
 
 extern crate config;
 
-use std::net::{Ipv4Addr};
+use std::env;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
 
-use config::{Config, File as CFile, FileFormat};
-use serde::{Deserialize, Serialize};
+use config::{Config, File as CFile, FileFormat, Value, ValueKind};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::{self, Visitor};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+use crate::cidr::{self, addr_bits, Cidr};
+use crate::origin::{detect_origin, detect_origin6};
+use crate::packet::same_family;
+use crate::shred::{DEFAULT_CHUNK_SIZE, DEFAULT_FRAGMENT_SIZE};
+
+/// How the ClientHello of an application is cut into pieces.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum Strategy {
+    /// pieces of `chunk_size` bytes
+    Fixed {
+        #[serde(default = "default_chunk_size")]
+        chunk_size: usize,
+    },
+    /// cuts around the SNI hostname, see `SniPosition`
+    Sni {
+        #[serde(default)]
+        position: SniPosition,
+    },
+    /// pieces of a random size between `min_size` and `max_size` bytes
+    Random { min_size: usize, max_size: usize },
+    /// `count` pieces of (roughly) equal size
+    Pieces { count: usize },
+}
+
+/// Where the `sni` strategy places its cuts.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SniPosition {
+    /// right before the server_name extension
+    BeforeExtension,
+    /// in the middle of the hostname
+    #[default]
+    MidHostname,
+    /// after each label of the hostname
+    EachLabel,
+}
+
+impl Default for Strategy {
+    fn default() -> Strategy {
+        Strategy::Fixed { chunk_size: DEFAULT_CHUNK_SIZE }
+    }
+}
+
+fn default_chunk_size() -> usize {
+    DEFAULT_CHUNK_SIZE
+}
+
+/// Sends the ClientHello as ip fragments instead of tcp segments.
+///
+/// Conntrack reassembles the fragments and refragments them on the way out, only their size
+/// survives that, so there are no knobs for their order or overlaps. Configs that still set
+/// `reverse` or `overlap` are rejected instead of silently doing nothing.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct IpFragmentation {
+    /// bytes of ip payload per fragment, a multiple of 8
+    #[serde(default = "default_fragment_size")]
+    pub fragment_size: usize,
+}
+
+fn default_fragment_size() -> usize {
+    DEFAULT_FRAGMENT_SIZE
+}
+
+/// A single port or an inclusive range of ports, written as `443` or `"8000-8100"` in the config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        self.start <= port && port <= self.end
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+impl Serialize for PortRange {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.start == self.end {
+            serializer.serialize_u16(self.start)
+        } else {
+            serializer.collect_str(self)
+        }
+    }
+}
+
+struct PortRangeVisitor;
+
+impl<'de> Visitor<'de> for PortRangeVisitor {
+    type Value = PortRange;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a port number or a range like \"8000-8100\"")
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<PortRange, E> {
+        let port = u16::try_from(v).map_err(|_| E::custom(format!("port {} out of range", v)))?;
+        Ok(PortRange { start: port, end: port })
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<PortRange, E> {
+        let port = u16::try_from(v).map_err(|_| E::custom(format!("port {} out of range", v)))?;
+        Ok(PortRange { start: port, end: port })
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<PortRange, E> {
+        let parse = |s: &str| s.trim().parse::<u16>().map_err(|_| E::custom(format!("invalid port range \"{}\"", v)));
+        let range = match v.split_once('-') {
+            Some((start, end)) => PortRange { start: parse(start)?, end: parse(end)? },
+            None => {
+                let port = parse(v)?;
+                PortRange { start: port, end: port }
+            }
+        };
+        if range.start > range.end {
+            return Err(E::custom(format!("invalid port range \"{}\"", v)));
+        }
+        Ok(range)
+    }
+}
+
+impl<'de> Deserialize<'de> for PortRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<PortRange, D::Error> {
+        deserializer.deserialize_any(PortRangeVisitor)
+    }
+}
+
+/// An entry of `Application::dest`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Destination {
+    Prefix(Cidr),
+    /// a domain name, resolved while running, see `resolver`
+    Domain(String),
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Destination::Prefix(cidr) => write!(f, "{}", cidr),
+            Destination::Domain(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+impl FromStr for Destination {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Destination, String> {
+        let ipv4 = s.starts_with(|c: char| c.is_ascii_digit()) && !s.contains(|c: char| c.is_ascii_alphabetic());
+        if ipv4 || s.contains(':') {
+            return Ok(Destination::Prefix(s.parse()?));
+        }
+
+        let valid = !s.is_empty() && s.len() <= 253 && s.trim_end_matches('.').split('.').all(|label| {
+            !label.is_empty() && label.len() <= 63
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+                && !label.starts_with('-') && !label.ends_with('-')
+        });
+        if !valid {
+            return Err(format!("\"{}\" is neither an address, a prefix nor a domain name", s));
+        }
+        Ok(Destination::Domain(s.trim_end_matches('.').to_lowercase()))
+    }
+}
+
+impl Destination {
+    /// domain names only resolve to IPv4 addresses
+    pub fn is_ipv6(&self) -> bool {
+        matches!(self, Destination::Prefix(cidr) if cidr.is_ipv6())
+    }
+}
+
+impl Serialize for Destination {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Destination {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Destination, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+/// Where `ConfigFile::origin` and `ConfigFile::origin6` come from.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Origin {
+    Address(IpAddr),
+    /// the primary address of an interface, followed as it changes
+    Interface(String),
+    /// the primary address of the interface of the default route, followed as it changes
+    #[default]
+    DefaultRoute,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Origin::Address(addr) => write!(f, "{}", addr),
+            Origin::Interface(name) => write!(f, "{}", name),
+            Origin::DefaultRoute => write!(f, "default"),
+        }
+    }
+}
+
+impl FromStr for Origin {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Origin, String> {
+        if let Ok(addr) = s.parse::<IpAddr>() {
+            return Ok(Origin::Address(addr));
+        }
+        if s == "default" {
+            return Ok(Origin::DefaultRoute);
+        }
+
+        // the limits of linux interface names
+        if s.is_empty() || s.len() > 15 || s.contains(|c: char| c == '/' || c == ':' || c.is_whitespace()) {
+            return Err(format!("\"{}\" is neither an address nor an interface name", s));
+        }
+        Ok(Origin::Interface(s.to_string()))
+    }
+}
+
+impl Serialize for Origin {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Origin {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Origin, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+/// The phony prefix set aside for the addresses a domain name in `dest` resolves to.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DomainBlock {
+    pub name: String,
+    pub phony: Cidr,
+}
+
+/// A destination prefix of an application and the phony prefix of the same size it's NATed
+/// to, the n-th address of one stands for the n-th address of the other.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct Mapping {
+    pub dest: Cidr,
+    pub phony: Cidr,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct Application {
     pub name: String,
-    pub dest: Ipv4Addr,
+    #[serde(deserialize_with = "cidr::one_or_many")]
+    pub dest: Vec<Destination>,
+    /// how many of the addresses each domain name in `dest` resolves to are intercepted
+    #[serde(default = "default_domain_addresses")]
+    pub domain_addresses: usize,
     pub phony: Option<Ipv4Addr>,
+    /// where the phony prefixes of the IPv6 destinations start
+    pub phony6: Option<Ipv6Addr>,
     pub origin: Option<Ipv4Addr>,
-    pub ports: Option<Vec<u16>>,
+    pub origin6: Option<Ipv6Addr>,
+    /// set when `origin` is taken from an interface of `ConfigFile::origin` and has to follow
+    /// its address
+    #[serde(skip)]
+    pub follows_origin: bool,
+    /// the same for `origin6`
+    #[serde(skip)]
+    pub follows_origin6: bool,
+    pub ports: Option<Vec<PortRange>>,
+    #[serde(default)]
+    pub strategy: Strategy,
+    pub ip_fragmentation: Option<IpFragmentation>,
+    #[serde(default)]
+    pub tls_record_split: bool,
+    pub shred_bytes: Option<u64>,
+    pub shred_packets: Option<u64>,
+    #[serde(default)]
     pub state: u8,
+    /// filled in from `dest` and `phony` by `allocate_phonies`, and from the resolved
+    /// domain names by `resolver::refresh_domains`
+    #[serde(skip)]
+    pub mappings: Vec<Mapping>,
+    #[serde(skip)]
+    pub domains: Vec<DomainBlock>,
+}
+
+fn default_domain_addresses() -> usize {
+    4
+}
+
+/// What installs the nat rules.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FirewallBackend {
+    /// the `iptables` binary, whichever backend it's built for
+    Iptables,
+    /// the `iptables-nft` binary
+    IptablesNft,
+    /// a table of its own replaced atomically through `nft`
+    Nftables,
+}
+
+/// How the packets are spread over the threads, the packets of a flow always stay in order.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scheduling {
+    /// every application has a thread of its own, shared round-robin when there are more
+    /// applications than threads
+    Application,
+    /// every flow goes to a thread picked by hashing its addresses and ports, so the flows of
+    /// a single application use all the threads
+    Flow,
+}
+
+/// What happens to a packet whose thread already has `ConfigFile::queue_depth` packets waiting.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+    /// the packet is dropped
+    DropNewest,
+    /// the packet that has been waiting the longest is dropped to make room
+    DropOldest,
+    /// reading from the tun stops until there is room
+    Block,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ConfigFile {
     pub name: String,
     pub num_threads: usize,
-    pub origin: Ipv4Addr,
+    pub scheduling: Scheduling,
+    /// how many packets can wait for each thread
+    pub queue_depth: usize,
+    pub overflow_policy: OverflowPolicy,
+    /// an address, or an interface whose address is used, the interface of the default
+    /// route if it's left out
+    #[serde(default)]
+    pub origin: Origin,
+    /// where the IPv6 traffic leaves from, `origin` is used if it's an interface
+    pub origin6: Option<Origin>,
     pub address: Ipv4Addr,
     pub subnet_mask: Ipv4Addr,
+    /// an IPv6 address for the tun, needed to intercept IPv6 destinations
+    pub address6: Option<Ipv6Addr>,
+    pub prefix6: u8,
     pub mtu: u16,
+    pub flow_timeout: u64,
+    /// idle timeout of the connections whose ClientHello got longer by the record split
+    pub translated_flow_timeout: u64,
     pub phony_range_start: Option<u8>,
-    pub applications: Vec<Application>,
+    /// resolve domain names from this hosts file instead of the system resolver
+    pub hosts_file: Option<String>,
+    /// seconds between resolving the domain names in `dest` again
+    pub resolve_interval: u64,
+    /// a directory of files with more applications, relative to the config file
+    pub include_dir: Option<String>,
+    pub firewall_backend: FirewallBackend,
+    /// shared with the packets in flight, changed through `Arc::make_mut`
+    pub applications: Vec<Arc<Application>>,
+}
+
+impl ConfigFile {
+    /// Where the IPv6 origin comes from, `None` if the tun has no IPv6 address or if it
+    /// can't be told.
+    pub fn ipv6_origin(&self) -> Option<&Origin> {
+        self.address6?;
+        match (&self.origin6, &self.origin) {
+            (Some(origin), _) => Some(origin),
+            (None, Origin::Address(_)) => None,
+            (None, origin) => Some(origin),
+        }
+    }
 }
 
-fn config_sanity_check(config: &mut ConfigFile) -> Result<(), String> {
+/// The configuration as shared with the threads that change it while running.
+pub type SharedConfig = Arc<RwLock<ConfigFile>>;
+
+/// Validates the config and fills in what's derived from it, every problem found is
+/// reported, one per line.
+///
+/// On a reload `running` holds the applications shredder runs with, see `reallocate_phonies`.
+fn config_sanity_check(config: &mut ConfigFile, running: &[Arc<Application>]) -> Result<(), String> {
+    let mut errors = Vec::new();
+
+    // the tun device, the firewall chains and the nftables table are named after it
+    if config.name.is_empty() || config.name.len() > 15
+        || !config.name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)) {
+        errors.push(format!("name has to be 1 to 15 letters, digits, '-', '_' or '.', not {:?}", config.name));
+    }
+
     // num threads
-    if config.num_threads > 128 {
-        return Err("Seriosuly?".into());
+    if config.num_threads == 0 || config.num_threads > 128 {
+        errors.push(format!("num_threads has to be between 1 and 128, not {}", config.num_threads));
+    }
+    if config.queue_depth == 0 {
+        errors.push("queue_depth has to be at least 1".to_string());
     }
 
+    let subnet = match Cidr::from_netmask(config.address, config.subnet_mask) {
+        Ok(s) => Some(s),
+        Err(m) => {
+            errors.push(format!("subnet_mask: {}", m));
+            None
+        }
+    };
+
+    let subnet6 = config.address6.and_then(|addr| match Cidr::containing(IpAddr::V6(addr), config.prefix6) {
+        Ok(s) => Some(s),
+        Err(m) => {
+            errors.push(format!("prefix6: {}", m));
+            None
+        }
+    });
 
     if config.phony_range_start.is_none() {
-        config.phony_range_start = Some(config.address.octets()[3] + 1);
+        match config.address.octets()[3].checked_add(1) {
+            Some(start) => config.phony_range_start = Some(start),
+            None => errors.push(format!("address {} leaves no room for phony addresses after it, set phony_range_start", config.address)),
+        }
     }
 
-    let addr = config.address.octets();
-    for pos in 0..config.applications.len() {
-        if config.applications[pos].phony.is_none() {
-            let phony_addr = Ipv4Addr::new(addr[0], addr[1], addr[2], config.phony_range_start.unwrap() + pos as u8);
-            config.applications[pos].phony = Some(phony_addr);
+    for (pos, app) in config.applications.iter().enumerate() {
+        if config.applications[..pos].iter().any(|other| other.name == app.name) {
+            errors.push(format!("{}: there is another application with the same name", app.name));
         }
-        if config.applications[pos].origin.is_none() {
-            config.applications[pos].origin = Some(config.origin.clone())
+        if let Err(m) = strategy_sanity_check(app) {
+            errors.push(m);
         }
+        if let Err(m) = fragmentation_sanity_check(app) {
+            errors.push(m);
+        }
+    }
+
+    if config.phony_range_start.is_some() {
+        let subnets: Vec<Cidr> = subnet.into_iter().chain(subnet6).collect();
+        let allocated = if running.is_empty() {
+            allocate_phonies(config, &mut errors)
+        } else {
+            reallocate_phonies(config, running, &subnets, &mut errors)
+        };
+        phony_sanity_check(config, &subnets, &allocated, &mut errors);
+    }
+
+    if config.address6.is_some() && config.ipv6_origin().is_none() {
+        errors.push("origin6 has to be set when origin is an address".to_string());
+    }
+
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
     }
 
-    if config.applications.len() < config.num_threads {
-        eprintln!("Warning: you have configured shredder to use {} threads when there are only {} aplications, this renders {} threads completely obsolete",
+    if config.scheduling == Scheduling::Application && config.applications.len() < config.num_threads {
+        eprintln!("Warning: you have configured shredder to use {} threads when there are only {} aplications, this renders {} threads completely obsolete, set scheduling to \"flow\" to use them",
                   config.num_threads,
                   config.applications.len(),
                   config.num_threads - config.applications.len());
     }
 
-    // address
     Ok(())
 }
 
-pub fn read_config_file(config_file: String) -> Result<ConfigFile, String> {
+/// Fills in the origin of the applications that don't have their own from the addresses of
+/// the interfaces, which `check` leaves alone so it works on machines without them.
+pub fn detect_origins(config: &mut ConfigFile) -> Result<(), String> {
+    let mut errors = Vec::new();
+    let origin = match detect_origin(&config.origin) {
+        Ok(s) => Some(s),
+        Err(m) => {
+            errors.push(format!("origin: {}", m));
+            None
+        }
+    };
+    let origin6 = match config.ipv6_origin() {
+        Some(source) => match detect_origin6(source) {
+            Ok(s) => Some(s),
+            Err(m) => {
+                errors.push(format!("origin6: {}", m));
+                None
+            }
+        },
+        None => None,
+    };
+    let follows6 = !matches!(config.ipv6_origin(), Some(Origin::Address(_)));
+    for app in config.applications.iter_mut() {
+        let app = Arc::make_mut(app);
+        if app.origin.is_none() {
+            app.origin = origin;
+            app.follows_origin = !matches!(config.origin, Origin::Address(_));
+        }
+        if app.origin6.is_none() {
+            app.origin6 = origin6;
+            app.follows_origin6 = follows6 && origin6.is_some();
+        }
+    }
+
+
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+    Ok(())
+}
+
+/// Checks that the phony prefixes of the applications that got them all lie inside the
+/// subnet of the tun device of their family and don't collide with its address or with
+/// each other.
+fn phony_sanity_check(config: &ConfigFile, subnets: &[Cidr], allocated: &[bool], errors: &mut Vec<String>) {
+    let mut taken: Vec<(&str, Cidr)> = Vec::new();
+    for (app, _) in config.applications.iter().zip(allocated).filter(|(_, ok)| **ok) {
+        let phonies = app.mappings.iter().map(|m| m.phony).chain(app.domains.iter().map(|d| d.phony));
+        for phony in phonies {
+            if let Some(subnet) = subnets.iter().find(|s| s.is_ipv6() == phony.is_ipv6() && !s.covers(&phony)) {
+                errors.push(format!("{}: phony {} is outside of the tun subnet {}", app.name, phony, subnet));
+            }
+            if let Some(address) = same_family(&phony.addr, Some(config.address), config.address6).filter(|a| phony.contains(*a)) {
+                errors.push(format!("{}: phony {} collides with the tun address {}", app.name, phony, address));
+            }
+            if let Some((other, _)) = taken.iter().find(|(_, p)| p.overlaps(&phony)) {
+                errors.push(format!("{}: phony {} collides with the phony addresses of {}", app.name, phony, other));
+            }
+            taken.push((&app.name, phony));
+        }
+    }
+}
+
+/// Lays out the phony prefixes: every destination prefix gets a phony prefix of the same
+/// size, aligned to its size, right after the previous one. An application with a `phony`
+/// gets its prefixes starting there instead, but still takes up its space in the sequence.
+/// IPv6 destinations get theirs the same way from the address after `address6` on, or
+/// from `phony6`.
+///
+/// Returns which applications got their phony prefixes, the others are reported in `errors`.
+fn allocate_phonies(config: &mut ConfigFile, errors: &mut Vec<String>) -> Vec<bool> {
+    let mut next = phony_starts(config);
+
+    let mut allocated = Vec::with_capacity(config.applications.len());
+    for app in config.applications.iter_mut() {
+        match allocate_app_phonies(Arc::make_mut(app), &mut next) {
+            Ok(()) => allocated.push(true),
+            Err(m) => {
+                errors.push(m);
+                allocated.push(false);
+            }
+        }
+    }
+    allocated
+}
+
+/// Where the IPv4 and the IPv6 phony prefixes start, `None` for IPv6 if the tun has no IPv6
+/// address.
+fn phony_starts(config: &ConfigFile) -> [Option<u128>; 2] {
+    let addr = config.address.octets();
+    let start = Ipv4Addr::new(addr[0], addr[1], addr[2], config.phony_range_start.unwrap());
+    [Some(u32::from(start) as u128), config.address6.map(|addr| u128::from(addr) + 1)]
+}
+
+/// Lays out the phony prefixes again for a reload. Unlike at startup the order of the
+/// applications doesn't matter: the ones still in `running` keep their phony prefixes as long
+/// as their destinations fit in them, so conntrack keeps sending their flows to the right
+/// place. The others get space no application uses, where `running` had phony prefixes only
+/// once nothing else is left, old flows may still go there.
+///
+/// Returns which applications got their phony prefixes, the others are reported in `errors`.
+fn reallocate_phonies(config: &mut ConfigFile, running: &[Arc<Application>], subnets: &[Cidr], errors: &mut Vec<String>) -> Vec<bool> {
+    let starts = phony_starts(config);
+    let mut allocated: Vec<bool> = config.applications.iter().map(|app| match dest_sanity_check(app) {
+        Ok(()) => true,
+        Err(m) => {
+            errors.push(m);
+            false
+        }
+    }).collect();
+
+    // the space the running applications had, by family
+    let previous_ranges = |app: &Application| {
+        let mut ranges: [Option<(u128, u128)>; 2] = [None, None];
+        for phony in app.mappings.iter().map(|m| m.phony).chain(app.domains.iter().map(|d| d.phony)) {
+            let (start, end) = (addr_bits(phony.addr), addr_bits(phony.addr).saturating_add(phony.size()));
+            let range = ranges[phony.is_ipv6() as usize].get_or_insert((start, end));
+            *range = (range.0.min(start), range.1.max(end));
+        }
+        ranges
+    };
+
+    // the applications with a phony of their own first, then the ones that still fit in their
+    // previous space, then the ones that grew past it if there's room
+    let mut phonies = vec![[None, None]; config.applications.len()];
+    let mut taken: [Vec<(u128, u128)>; 2] = [Vec::new(), Vec::new()];
+    for pass in 0..3 {
+        let keep = pass > 0;
+        for (pos, app) in config.applications.iter().enumerate() {
+            if !allocated[pos] {
+                continue;
+            }
+            let previous = running.iter().find(|r| r.name == app.name);
+            let ranges = previous.map(|r| previous_ranges(r)).unwrap_or_default();
+            for (family, ipv6) in [false, true].into_iter().enumerate() {
+                if phonies[pos][family].is_some() || !app.dest.iter().any(|d| d.is_ipv6() == ipv6) {
+                    continue;
+                }
+                let own = if ipv6 { app.phony6.map(IpAddr::V6) } else { app.phony.map(IpAddr::V4) };
+                let previous = previous.and_then(|r| if ipv6 { r.phony6.map(IpAddr::V6) } else { r.phony.map(IpAddr::V4) });
+                let at = match (own, previous) {
+                    (Some(own), _) if !keep => own,
+                    (None, Some(previous)) if keep => previous,
+                    _ => continue,
+                };
+                match layout(app, ipv6, addr_bits(at), true) {
+                    Ok((laid, end)) => {
+                        let range = (addr_bits(at), end);
+                        let grew = ranges[family].is_none_or(|(_, previous_end)| end > previous_end);
+                        if pass == 1 && grew {
+                            continue;
+                        }
+                        // one that doesn't fit any more moves, it's laid out with the new ones
+                        if keep && overlapping(&taken[family], range).is_some() {
+                            continue;
+                        }
+                        taken[family].push(range);
+                        phonies[pos][family] = Some(laid);
+                    },
+                    Err(m) => if !keep {
+                        errors.push(m);
+                        allocated[pos] = false;
+                    },
+                }
+            }
+        }
+    }
+
+    let mut freed = [Vec::new(), Vec::new()];
+    for app in running.iter() {
+        for phony in app.mappings.iter().map(|m| m.phony).chain(app.domains.iter().map(|d| d.phony)) {
+            let start = addr_bits(phony.addr);
+            freed[phony.is_ipv6() as usize].push((start, start.saturating_add(phony.size())));
+        }
+    }
+
+    for (pos, app) in config.applications.iter_mut().enumerate() {
+        if !allocated[pos] {
+            continue;
+        }
+        let app = Arc::make_mut(app);
+        for (family, ipv6) in [false, true].into_iter().enumerate() {
+            if phonies[pos][family].is_some() || !app.dest.iter().any(|d| d.is_ipv6() == ipv6) {
+                continue;
+            }
+            let cursor = match starts[family] {
+                Some(s) => s,
+                None => {
+                    errors.push(format!("{}: address6 has to be set to intercept IPv6 destinations", app.name));
+                    allocated[pos] = false;
+                    break;
+                }
+            };
+
+            let mut avoid = taken[family].clone();
+            avoid.extend(freed[family].iter().copied());
+            let inside = |laid: &Vec<Cidr>| subnets.iter()
+                .filter(|s| s.is_ipv6() == ipv6)
+                .all(|s| laid.iter().all(|phony| s.covers(phony)));
+            let room = match find_room(app, ipv6, cursor, &avoid) {
+                Ok((laid, end)) if inside(&laid) => Ok((laid, end)),
+                _ => find_room(app, ipv6, cursor, &taken[family]),
+            };
+            match room {
+                Ok((laid, end)) => {
+                    taken[family].push((addr_bits(laid[0].addr), end));
+                    phonies[pos][family] = Some(laid);
+                },
+                Err(m) => {
+                    errors.push(m);
+                    allocated[pos] = false;
+                    break;
+                }
+            }
+        }
+        if allocated[pos] {
+            assign_phonies(app, phonies[pos].clone().map(Option::unwrap_or_default));
+        }
+    }
+    allocated
+}
+
+/// The end of a range in `ranges` that overlaps `range`, the furthest one if there are several.
+fn overlapping(ranges: &[(u128, u128)], range: (u128, u128)) -> Option<u128> {
+    ranges.iter().filter(|(start, end)| *start < range.1 && range.0 < *end).map(|(_, end)| *end).max()
+}
+
+/// Lays out the phony prefixes for the `dest` entries of `app` of one family from `cursor`
+/// on, past anything in `avoid`.
+fn find_room(app: &Application, ipv6: bool, mut cursor: u128, avoid: &[(u128, u128)]) -> Result<(Vec<Cidr>, u128), String> {
+    loop {
+        let (laid, end) = layout(app, ipv6, cursor, false)?;
+        match overlapping(avoid, (addr_bits(laid[0].addr), end)) {
+            Some(past) => cursor = past,
+            None => return Ok((laid, end)),
+        }
+    }
+}
+
+fn dest_sanity_check(app: &Application) -> Result<(), String> {
+    if app.dest.is_empty() {
+        return Err(format!("{}: dest can not be empty", app.name));
+    }
+
+    if app.domain_addresses == 0 || app.domain_addresses > 256 {
+        return Err(format!("{}: domain_addresses has to be between 1 and 256", app.name));
+    }
+    Ok(())
+}
+
+/// Lays out the phony prefixes of `app` from `next`, which holds where the IPv4 and the
+/// IPv6 ones go, `None` for IPv6 if the tun has no IPv6 address.
+fn allocate_app_phonies(app: &mut Application, next: &mut [Option<u128>; 2]) -> Result<(), String> {
+    dest_sanity_check(app)?;
+
+    let mut phonies = [Vec::new(), Vec::new()];
+    for (family, ipv6) in [false, true].into_iter().enumerate() {
+        if !app.dest.iter().any(|d| d.is_ipv6() == ipv6) {
+            continue;
+        }
+        let cursor = match next[family] {
+            Some(s) => s,
+            None => { return Err(format!("{}: address6 has to be set to intercept IPv6 destinations", app.name)); }
+        };
+
+        let (laid, end) = layout(app, ipv6, cursor, false)?;
+        next[family] = Some(end);
+
+        let fixed = if ipv6 { app.phony6.map(IpAddr::V6) } else { app.phony.map(IpAddr::V4) };
+        phonies[family] = match fixed {
+            Some(phony) => layout(app, ipv6, addr_bits(phony), true)?.0,
+            None => laid,
+        };
+    }
+    assign_phonies(app, phonies);
+    Ok(())
+}
+
+/// Sets the phony prefixes of `app`, the IPv4 and the IPv6 ones in the order of `dest`.
+fn assign_phonies(app: &mut Application, phonies: [Vec<Cidr>; 2]) {
+    if let Some(IpAddr::V4(addr)) = phonies[0].first().map(|p| p.addr) {
+        app.phony = Some(addr);
+    }
+    if let Some(IpAddr::V6(addr)) = phonies[1].first().map(|p| p.addr) {
+        app.phony6 = Some(addr);
+    }
+
+    app.mappings.clear();
+    app.domains.clear();
+    let mut phonies = phonies.map(|p| p.into_iter());
+    for dest in app.dest.iter() {
+        let phony = phonies[dest.is_ipv6() as usize].next().unwrap();
+        match dest {
+            Destination::Prefix(cidr) => app.mappings.push(Mapping { dest: *cidr, phony }),
+            Destination::Domain(name) => app.domains.push(DomainBlock { name: name.clone(), phony }),
+        }
+    }
+}
+
+/// Places the phony prefixes for the `dest` entries of `app` of one family from `cursor` on,
+/// returns them and where they end. A domain name gets room for `domain_addresses` addresses.
+fn layout(app: &Application, ipv6: bool, mut cursor: u128, fixed: bool) -> Result<(Vec<Cidr>, u128), String> {
+    let (width, field) = if ipv6 { (128, "phony6") } else { (32, "phony") };
+    let mut phonies = Vec::with_capacity(app.dest.len());
+    for dest in app.dest.iter().filter(|d| d.is_ipv6() == ipv6) {
+        let prefix = match dest {
+            Destination::Prefix(cidr) => cidr.prefix,
+            Destination::Domain(_) => 32 - app.domain_addresses.next_power_of_two().trailing_zeros() as u8,
+        };
+        let size_bits = width - prefix;
+        let (start, end) = 1u128.checked_shl(size_bits as u32)
+            .and_then(|size| {
+                let start = cursor.checked_next_multiple_of(size)?;
+                Some((start, start.checked_add(size)?))
+            })
+            .filter(|(_, end)| ipv6 || *end <= 1 << 32)
+            .ok_or(format!("{}: ran out of phony addresses for {}", app.name, dest))?;
+        if fixed && phonies.is_empty() && start != cursor {
+            return Err(format!("{}: {} has to be aligned to /{} to map {}", app.name, field, prefix, dest));
+        }
+
+        phonies.push(Cidr::from_bits(start, size_bits, ipv6)?);
+        cursor = end;
+    }
+    Ok((phonies, cursor))
+}
+
+fn strategy_sanity_check(app: &Application) -> Result<(), String> {
+    match app.strategy {
+        Strategy::Fixed { chunk_size: 0 } => {
+            Err(format!("{}: strategy chunk_size must be at least 1", app.name))
+        },
+        Strategy::Random { min_size, max_size } if min_size == 0 || min_size > max_size => {
+            Err(format!("{}: strategy needs 0 < min_size <= max_size", app.name))
+        },
+        Strategy::Pieces { count: 0 } => {
+            Err(format!("{}: strategy count must be at least 1", app.name))
+        },
+        _ => Ok(()),
+    }
+}
+
+fn fragmentation_sanity_check(app: &Application) -> Result<(), String> {
+    if let Some(frag) = &app.ip_fragmentation {
+        if frag.fragment_size == 0 || frag.fragment_size % 8 != 0 {
+            return Err(format!("{}: ip_fragmentation fragment_size must be a positive multiple of 8", app.name));
+        }
+    }
+    Ok(())
+}
+
+/// Where the config is read from.
+#[derive(Debug, Clone)]
+pub struct ConfigSource {
+    pub path: String,
+    /// overrides the format guessed from the extension of `path`
+    pub format: Option<String>,
+    /// `key=value` pairs from the command line, applied on top of the file and the environment
+    pub overrides: Vec<String>,
+}
+
+/// Prefix of the environment variables that override config fields.
+pub const ENV_PREFIX: &str = "SHREDDER_";
+
+fn format_named(name: &str) -> Option<FileFormat> {
+    match &name.to_lowercase()[..] {
+        "json" => Some(FileFormat::Json),
+        "json5" => Some(FileFormat::Json5),
+        "yaml" | "yml" => Some(FileFormat::Yaml),
+        "toml" => Some(FileFormat::Toml),
+        "ini" => Some(FileFormat::Ini),
+        "ron" => Some(FileFormat::Ron),
+        _ => None,
+    }
+}
+
+impl ConfigSource {
+    /// The format of the file, from `format` if it's set and from the extension otherwise.
+    /// A file without an extension is taken to be json.
+    pub fn file_format(&self) -> Result<FileFormat, String> {
+        if let Some(format) = &self.format {
+            return format_named(format).ok_or(format!("unknown config format \"{}\"", format));
+        }
+
+        match Path::new(&self.path).extension().and_then(|e| e.to_str()) {
+            None => Ok(FileFormat::Json),
+            Some(extension) => format_named(extension)
+                .ok_or(format!("can not tell the format of {} from its extension, use --format", self.path)),
+        }
+    }
+}
+
+/// The config fields set through the environment: `SHREDDER_SUBNET_MASK` sets `subnet_mask`
+/// and a double underscore separates the parts of a path, as in `SHREDDER_APPLICATIONS__TEST0__ORIGIN`.
+fn env_overrides() -> Vec<(String, String)> {
+    let mut overrides: Vec<(String, String)> = env::vars()
+        .filter_map(|(name, value)| {
+            let key = name.strip_prefix(ENV_PREFIX)?;
+            Some((key.to_lowercase().replace("__", "."), value))
+        })
+        .collect();
+    // the order of the environment is arbitrary, at least make it the same every time
+    overrides.sort();
+    overrides
+}
+
+fn cli_overrides(overrides: &[String]) -> Result<Vec<(String, String)>, String> {
+    overrides.iter().map(|o| {
+        match o.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => Ok((key.trim().to_string(), value.to_string())),
+            _ => Err(format!("invalid override \"{}\", expected key=value", o)),
+        }
+    }).collect()
+}
+
+/// Splits an override of a field of one application, `applications.<app>.<field>`.
+fn application_override(key: &str) -> Option<(&str, &str)> {
+    let parts: Vec<&str> = key.splitn(3, '.').collect();
+    if parts[0] == "applications" && parts.len() == 3 {
+        Some((parts[1], parts[2]))
+    } else {
+        None
+    }
+}
+
+fn application_named(app: &str, names: &[String]) -> Option<usize> {
+    names.iter().position(|name| name.eq_ignore_ascii_case(app))
+}
+
+/// Numbers and booleans given as text are passed on as such, the config crate converts them
+/// back for string fields but not inside tagged enums like `strategy`. Lists are written
+/// like `[443, "8000-8100"]`, their entries can't contain commas.
+fn override_value(value: String) -> ValueKind {
+    let trimmed = value.trim();
+    if let Some(list) = trimmed.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+        let entries = list.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let entry = entry.strip_prefix('"').and_then(|s| s.strip_suffix('"')).unwrap_or(entry);
+                Value::new(None, override_value(entry.to_string()))
+            })
+            .collect();
+        ValueKind::Array(entries)
+    } else if let Ok(n) = value.parse::<i64>() {
+        ValueKind::I64(n)
+    } else if let Ok(b) = value.parse::<bool>() {
+        ValueKind::Boolean(b)
+    } else {
+        ValueKind::String(value)
+    }
+}
+
+/// Names of the applications in a config, to resolve the overrides that refer to them.
+fn application_names(config: &Config) -> Vec<String> {
+    let apps = match config.get_array("applications") {
+        Ok(s) => s,
+        Err(_) => { return Vec::new(); }
+    };
+
+    apps.into_iter()
+        .map(|app| app.into_table().ok()
+            .and_then(|mut table| table.remove("name"))
+            .and_then(|name| name.into_string().ok())
+            .unwrap_or_default())
+        .collect()
+}
+
+#[derive(Deserialize)]
+struct Snippet {
+    applications: Vec<Application>,
+}
+
+/// Reads the applications in a file of `include_dir`, either a single application or a list
+/// of them under `applications`, with the overrides that refer to them applied.
+fn read_snippet(path: &Path, format: FileFormat, overrides: &[(String, String)], used: &mut [bool]) -> Result<Vec<Application>, String> {
+    let file = CFile::from(path).format(format);
+    let preview = Config::builder().add_source(file.clone()).build().map_err(|e| e.to_string())?;
+    let list = preview.get_array("applications").is_ok();
+    let names = if list {
+        application_names(&preview)
+    } else {
+        vec![preview.get_string("name").unwrap_or_default()]
+    };
+
+    let mut builder = Config::builder().add_source(file);
+    for (n, (key, value)) in overrides.iter().enumerate() {
+        let (index, field) = match application_override(key).and_then(|(app, field)| Some((application_named(app, &names)?, field))) {
+            Some(s) => s,
+            None => continue,
+        };
+        let path = if list { format!("applications[{}].{}", index, field) } else { field.to_string() };
+        builder = builder.set_override(path, override_value(value.clone())).map_err(|e| format!("override {}: {}", key, e))?;
+        used[n] = true;
+    }
+
+    let config = builder.build().map_err(|e| e.to_string())?;
+    if list {
+        config.try_deserialize::<Snippet>().map(|s| s.applications).map_err(|e| e.to_string())
+    } else {
+        config.try_deserialize::<Application>().map(|app| vec![app]).map_err(|e| e.to_string())
+    }
+}
+
+/// Adds the applications of every file in `include_dir` to the config, in the order of their
+/// names. Files with an extension that isn't a config format are skipped.
+fn include_applications(config: &mut ConfigFile, config_path: &str, overrides: &[(String, String)], used: &mut [bool]) -> Result<(), String> {
+    let dir = match &config.include_dir {
+        Some(s) => Path::new(config_path).parent().unwrap_or(Path::new("")).join(s),
+        None => { return Ok(()); }
+    };
+
+    let entries = fs::read_dir(&dir).map_err(|e| format!("include_dir {}: {}", dir.display(), e))?;
+    let mut paths: Vec<PathBuf> = entries.filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_file())
+        .collect();
+    paths.sort();
+
+    // where each application and explicitly set phony comes from
+    let mut names: Vec<(String, String)> = config.applications.iter().map(|app| (app.name.clone(), config_path.to_string())).collect();
+    let explicit_phonies = |app: &Application| -> Vec<IpAddr> {
+        app.phony.map(IpAddr::V4).into_iter().chain(app.phony6.map(IpAddr::V6)).collect()
+    };
+    let mut phonies: Vec<(IpAddr, String)> = config.applications.iter()
+        .flat_map(|app| explicit_phonies(app).into_iter().map(|phony| (phony, app.name.clone())))
+        .collect();
+    let mut errors = Vec::new();
+    for path in paths.iter() {
+        let format = match path.extension().and_then(|e| e.to_str()).and_then(format_named) {
+            Some(s) => s,
+            None => continue,
+        };
+        let apps = match read_snippet(path, format, overrides, used) {
+            Ok(s) => s,
+            Err(m) => {
+                errors.push(format!("{}: {}", path.display(), m));
+                continue;
+            }
+        };
+
+        for app in apps.into_iter() {
+            if let Some((_, other)) = names.iter().find(|(name, _)| *name == app.name) {
+                errors.push(format!("{}: defined in both {} and {}", app.name, other, path.display()));
+                continue;
+            }
+            let taken = explicit_phonies(&app).into_iter()
+                .find_map(|phony| phonies.iter().find(|(p, _)| *p == phony).map(|(_, other)| (phony, other)));
+            if let Some((phony, other)) = taken {
+                errors.push(format!("{}: phony {} in {} is already the phony of {}", app.name, phony, path.display(), other));
+                continue;
+            }
+
+            names.push((app.name.clone(), path.display().to_string()));
+            phonies.extend(explicit_phonies(&app).into_iter().map(|phony| (phony, app.name.clone())));
+            config.applications.push(Arc::new(app));
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+    Ok(())
+}
+
+pub fn read_config_file(source: &ConfigSource) -> Result<ConfigFile, String> {
+    load_config_file(source, &[])
+}
+
+/// Reads the config again while shredder runs with the applications in `running`, the ones
+/// that are still there keep their phony addresses.
+pub fn reread_config_file(source: &ConfigSource, running: &[Arc<Application>]) -> Result<ConfigFile, String> {
+    load_config_file(source, running)
+}
+
+fn load_config_file(source: &ConfigSource, running: &[Arc<Application>]) -> Result<ConfigFile, String> {
+    let format = source.file_format()?;
+    let file = CFile::new(&source.path, format);
     let mut config_builder = Config::builder()
-        .add_source(CFile::new(&config_file, FileFormat::Json))
-        .set_default("name", "shredder-tun").or_else(|e| {return Err(format!("default/name: {}", e.to_string()))}).unwrap()
-        .set_default("mtu", 1500).or_else(|e| {return Err(format!("default/mtu: {}", e.to_string()))}).unwrap();
+        .add_source(file.clone())
+        .set_default("name", "shredder-tun").map_err(|e| format!("default/name: {}", e)).unwrap()
+        .set_default("mtu", 1500).map_err(|e| format!("default/mtu: {}", e)).unwrap()
+        .set_default("flow_timeout", 300).map_err(|e| format!("default/flow_timeout: {}", e)).unwrap()
+        .set_default("translated_flow_timeout", 86400).map_err(|e| format!("default/translated_flow_timeout: {}", e)).unwrap()
+        .set_default("prefix6", 64).map_err(|e| format!("default/prefix6: {}", e)).unwrap()
+        .set_default("scheduling", "application").map_err(|e| format!("default/scheduling: {}", e)).unwrap()
+        .set_default("queue_depth", 1024).map_err(|e| format!("default/queue_depth: {}", e)).unwrap()
+        .set_default("overflow_policy", "drop-newest").map_err(|e| format!("default/overflow_policy: {}", e)).unwrap()
+        .set_default("resolve_interval", 300).map_err(|e| format!("default/resolve_interval: {}", e)).unwrap()
+        .set_default("firewall_backend", "iptables").map_err(|e| format!("default/firewall_backend: {}", e)).unwrap();
+
+    // the environment first so the command line wins
+    let mut overrides = env_overrides();
+    overrides.extend(cli_overrides(&source.overrides)?);
+    let mut used = vec![false; overrides.len()];
+    if !overrides.is_empty() {
+        let names = Config::builder().add_source(file.clone()).build()
+            .map(|c| application_names(&c))
+            .unwrap_or_default();
+        for (n, (key, value)) in overrides.iter().enumerate() {
+            let path = match application_override(key) {
+                None => key.clone(),
+                // an application can also be picked by its position in the list
+                Some((app, field)) => match application_named(app, &names).or(app.parse::<usize>().ok().filter(|i| *i < names.len())) {
+                    Some(index) => format!("applications[{}].{}", index, field),
+                    // it may be in one of the included files
+                    None => continue,
+                },
+            };
+            config_builder = config_builder.set_override(path, override_value(value.clone())).map_err(|e| format!("override {}: {}", key, e))?;
+            used[n] = true;
+        }
+    }
 
     let mut config: ConfigFile;
 
@@ -73,15 +1110,106 @@ pub fn read_config_file(config_file: String) -> Result<ConfigFile, String> {
         Ok(c) => {
             config = match c.try_deserialize() {
                 Ok(s)=>s,
-                Err(m) => { return Err(format!("Error while unpacking json config: {}", m.to_string())); }
+                Err(m) => { return Err(format!("Error while unpacking config: {}", m)); }
             }
         },
         Err(m) => {
-            return Err(format!("Error while reading config file: {}", m.to_string()));
+            return Err(format!("Error while reading config file: {}", m));
         }
     }
 
-    config_sanity_check(&mut config)?;
+    include_applications(&mut config, &source.path, &overrides, &mut used)?;
+    if let Some(n) = used.iter().position(|u| !u) {
+        let key = &overrides[n].0;
+        return Err(format!("can not override {}: there's no application named {}", key, application_override(key).unwrap().0));
+    }
+
+    config_sanity_check(&mut config, running)?;
 
     Ok(config)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads `json` from a file of its own in a directory named after the test, and reads it
+    /// as a reload would if there are `running` applications.
+    fn read(test: &str, json: &str, running: &[Arc<Application>]) -> ConfigFile {
+        read_with(test, json, &[], running)
+    }
+
+    /// The same with the `included` files, by name, in the `include_dir` "apps".
+    fn read_with(test: &str, json: &str, included: &[(&str, &str)], running: &[Arc<Application>]) -> ConfigFile {
+        let dir = env::temp_dir().join(format!("shredder-{}-{}", test, std::process::id()));
+        fs::create_dir_all(dir.join("apps")).unwrap();
+        for (name, app) in included.iter() {
+            fs::write(dir.join("apps").join(name), app).unwrap();
+        }
+        let path = dir.join("config.json");
+        fs::write(&path, json).unwrap();
+        let source = ConfigSource { path: path.to_str().unwrap().to_string(), format: None, overrides: Vec::new() };
+        let config = reread_config_file(&source, running).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        config
+    }
+
+    fn config(apps: &str) -> String {
+        format!(r#"{{"num_threads": 1, "address": "10.9.0.1", "subnet_mask": "255.255.255.224", "origin": "10.9.0.1",
+                    "include_dir": "apps", "applications": [{}]}}"#, apps)
+    }
+
+    fn phonies(config: &ConfigFile, name: &str) -> Vec<Mapping> {
+        config.applications.iter().find(|app| app.name == name).unwrap().mappings.clone()
+    }
+
+    #[test]
+    fn reload_keeps_the_phonies_of_the_other_applications() {
+        let a = r#"{"name": "a", "dest": "192.0.2.0/30"}"#;
+        let b = r#"{"name": "b", "dest": ["198.51.100.0/29", "198.51.100.64/30"]}"#;
+        let c = r#"{"name": "c", "dest": "203.0.113.7"}"#;
+        let d = r#"{"name": "d", "dest": "203.0.113.0/29"}"#;
+        let before = read("reload", &config(&[a, b, c].join(",")), &[]);
+
+        let after = read("reload", &config(&[a, c, d].join(",")), &before.applications);
+        assert_eq!(phonies(&after, "a"), phonies(&before, "a"));
+        assert_eq!(phonies(&after, "c"), phonies(&before, "c"));
+        // the new application stays out of the space b just freed
+        let freed: Vec<Cidr> = phonies(&before, "b").iter().map(|m| m.phony).collect();
+        let taken: Vec<Cidr> = before.applications.iter().flat_map(|app| app.mappings.iter().map(|m| m.phony)).collect();
+        for mapping in phonies(&after, "d") {
+            assert!(!freed.iter().chain(taken.iter()).any(|phony| phony.overlaps(&mapping.phony)), "{:?}", mapping);
+        }
+
+        // once there is no other room left, the freed space is used again
+        let e = r#"{"name": "e", "dest": "100.64.0.0/29"}"#;
+        let full = read("reload", &config(&[a, c, d, e].join(",")), &before.applications);
+        assert_eq!(phonies(&full, "d"), phonies(&after, "d"));
+        assert_eq!(phonies(&full, "e")[0].phony, freed[0]);
+    }
+
+    #[test]
+    fn reload_moves_what_no_longer_fits() {
+        let before = read("grow", &config(r#"{"name": "a", "dest": "192.0.2.1"}, {"name": "b", "dest": "192.0.2.2"}"#), &[]);
+        let after = read("grow", &config(r#"{"name": "a", "dest": ["192.0.2.1", "192.0.2.9"]}, {"name": "b", "dest": "192.0.2.2"}"#), &before.applications);
+        assert_eq!(phonies(&after, "b"), phonies(&before, "b"));
+        let a = phonies(&after, "a");
+        assert!(!a.iter().any(|m| m.phony.overlaps(&phonies(&after, "b")[0].phony)));
+    }
+
+    #[test]
+    fn reload_keeps_the_phonies_whatever_the_order_of_the_files() {
+        let main = config(r#"{"name": "main", "dest": "192.0.2.1"}"#);
+        let m = ("m.json", r#"{"name": "m", "dest": "198.51.100.0/30"}"#);
+        let z = ("z.yaml", "name: z\ndest: [203.0.113.1, 203.0.113.2]");
+        let before = read_with("include", &main, &[m, z], &[]);
+
+        // a file that sorts first puts its application before the others
+        let a = ("a.json", r#"{"applications": [{"name": "a", "dest": "100.64.0.1"}]}"#);
+        let after = read_with("include", &main, &[a, m, z], &before.applications);
+        assert_eq!(after.applications[1].name, "a");
+        for name in ["main", "m", "z"] {
+            assert_eq!(phonies(&after, name), phonies(&before, name));
+        }
+    }
+}
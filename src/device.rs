@@ -1,18 +1,14 @@
 
 extern crate tun;
 
-
-use std::net::{Ipv4Addr};
-use std::io::{self, Read, Write, ErrorKind};
-use std::time::Duration;
 use std::process::Command;
 
 use tun::{platform::linux::Device, Configuration};
 
 use crate::configfile::ConfigFile;
+use crate::firewall::{config_rules, Firewall};
 
-
-pub fn create_and_configure_device(config: &ConfigFile) -> Result<Device, String>{
+pub fn create_and_configure_device(config: &ConfigFile, firewall: &mut dyn Firewall) -> Result<Device, String>{
     let mut tun_config = Configuration::default();
     tun_config.name(config.name.clone());
     tun_config.address(config.address);
@@ -20,27 +16,35 @@ pub fn create_and_configure_device(config: &ConfigFile) -> Result<Device, String
     tun_config.mtu(config.mtu.into());
     tun_config.up();
     
-    let mut dev = tun::create(&tun_config).unwrap();
-    dev.set_nonblock().unwrap();
-    let start = config.phony_range_start;
-
-    for app in config.applications.iter() {
-        let dest_addr = &app.dest.to_string()[..];
-        let phony_addr = &app.phony.unwrap().to_string()[..];
-        let orig_addr = &app.origin.unwrap().to_string()[..];
-        Command::new("iptables").args(&["-t", "nat", "-A", "OUTPUT", "-d", dest_addr, "-j", "DNAT", "--to-destination", phony_addr]).output().expect("oops");
-        Command::new("iptables").args(&["-t", "nat", "-A", "POSTROUTING", "-s", phony_addr, "-j", "SNAT", "--to-source", orig_addr]).output().expect("oops");
+    let dev = tun::create(&tun_config).map_err(|e| format!("could not create {}: {}", config.name, e))?;
+    dev.set_nonblock().map_err(|e| format!("could not make {} non-blocking: {}", config.name, e))?;
+    if let Some(address6) = config.address6 {
+        // the tun crate only sets IPv4 addresses
+        add_address6(&config.name, &format!("{}/{}", address6, config.prefix6))?;
+    }
+
+    if firewall.remove_stale()? {
+        println!("removed the firewall rules left behind by a previous run");
     }
+    // on failure the firewall rolls back what it installed and dropping the device removes it
+    firewall.install(&config_rules(config))?;
 
     Ok(dev)
 }
 
-pub fn stop_and_clean_up_device(config: ConfigFile, _dev: Device){
-    for app in config.applications.iter() {
-        let dest_addr = &app.dest.to_string()[..];
-        let phony_addr = &app.phony.unwrap().to_string()[..];
-        let orig_addr = &app.origin.unwrap().to_string()[..];
-        Command::new("iptables").args(&["-t", "nat", "-A", "OUTPUT", "-d", dest_addr, "-j", "DNAT", "--to-destination", phony_addr]).output().expect("oops");
-        Command::new("iptables").args(&["-t", "nat", "-A", "POSTROUTING", "-s", phony_addr, "-j", "SNAT", "--to-source", orig_addr]).output().expect("oops");
+fn add_address6(name: &str, address: &str) -> Result<(), String> {
+    let output = Command::new("ip")
+        .args(["-6", "addr", "add", address, "dev", name])
+        .output()
+        .map_err(|e| format!("could not run ip: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("could not add {} to {}: {}", address, name, String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(())
+}
+
+pub fn stop_and_clean_up_device(firewall: &mut dyn Firewall){
+    if let Err(m) = firewall.teardown() {
+        eprintln!("Error while removing the firewall rules: {}", m);
     }
 }
@@ -0,0 +1,408 @@
+
+use std::io::Write;
+use std::iter;
+use std::net::IpAddr;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+
+use crate::cidr::Cidr;
+use crate::configfile::{Application, ConfigFile, FirewallBackend, Mapping, PortRange};
+use crate::packet::same_family;
+
+/// A nat rule shredder needs, whatever the backend.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NatRule {
+    /// locally generated traffic to `dest` goes to `phony` instead, address for address
+    Dnat { dest: Cidr, ports: Option<PortRange>, phony: Cidr },
+    /// traffic from `phony` leaves with `origin` as its source
+    Snat { phony: Cidr, origin: IpAddr },
+}
+
+impl NatRule {
+    pub fn is_ipv6(&self) -> bool {
+        match self {
+            NatRule::Dnat { phony, .. } | NatRule::Snat { phony, .. } => phony.is_ipv6(),
+        }
+    }
+}
+
+/// The rules for one mapping of an application.
+pub fn mapping_rules(app: &Application, mapping: &Mapping) -> Vec<NatRule> {
+    let mut rules = Vec::new();
+    match &app.ports {
+        None => rules.push(NatRule::Dnat { dest: mapping.dest, ports: None, phony: mapping.phony }),
+        Some(ports) => {
+            for range in ports.iter() {
+                rules.push(NatRule::Dnat { dest: mapping.dest, ports: Some(*range), phony: mapping.phony });
+            }
+        }
+    }
+    let origin = same_family(&mapping.phony.addr, app.origin, app.origin6).unwrap();
+    rules.push(NatRule::Snat { phony: mapping.phony, origin });
+    rules
+}
+
+/// The rules for every application in the config.
+pub fn config_rules(config: &ConfigFile) -> Vec<NatRule> {
+    let mut rules = Vec::new();
+    for app in config.applications.iter() {
+        for mapping in app.mappings.iter() {
+            rules.extend(mapping_rules(app, mapping));
+        }
+    }
+    rules
+}
+
+/// Installs and removes the nat rules.
+pub trait Firewall: Send {
+    /// Removes what a previous instance that didn't shut down cleanly left behind, tells
+    /// whether there was anything.
+    fn remove_stale(&mut self) -> Result<bool, String>;
+    /// Installs the rules at startup.
+    fn install(&mut self, rules: &[NatRule]) -> Result<(), String>;
+    fn add(&mut self, rules: &[NatRule]) -> Result<(), String>;
+    fn remove(&mut self, rules: &[NatRule]) -> Result<(), String>;
+    /// The commands `install` would run for `rules`, for showing them without running them.
+    fn commands(&self, rules: &[NatRule]) -> Vec<String>;
+    /// Removes everything that was installed, at shutdown. Does nothing if there is nothing.
+    /// Afterwards `add` and `remove` fail, the background threads may still be finishing up.
+    fn teardown(&mut self) -> Result<(), String>;
+}
+
+pub type SharedFirewall = Arc<Mutex<Box<dyn Firewall>>>;
+
+pub fn firewall_for(config: &ConfigFile) -> Box<dyn Firewall> {
+    match config.firewall_backend {
+        FirewallBackend::Iptables => Box::new(Iptables::new("iptables", config.address6.map(|_| "ip6tables"), &config.name)),
+        FirewallBackend::IptablesNft => Box::new(Iptables::new("iptables-nft", config.address6.map(|_| "ip6tables-nft"), &config.name)),
+        FirewallBackend::Nftables => Box::new(Nftables::new(&config.name)),
+    }
+}
+
+/// Keeps a late resolve or origin change from putting rules back after shutdown, which
+/// would recreate the nftables table.
+fn check_not_torn_down(torn_down: bool) -> Result<(), String> {
+    if torn_down {
+        return Err("the firewall rules have already been torn down".to_string());
+    }
+    Ok(())
+}
+
+/// Runs a firewall command, returns its exit status and what it wrote to stderr.
+fn execute(program: &str, args: &[String], stdin: Option<&str>) -> Result<(bool, String), String> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(if stdin.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("could not run {}: {}", program, e))?;
+
+    if let Some(input) = stdin {
+        child.stdin.take().unwrap().write_all(input.as_bytes()).map_err(|e| format!("{}: {}", program, e))?;
+    }
+    let output = child.wait_with_output().map_err(|e| format!("{}: {}", program, e))?;
+    Ok((output.status.success(), String::from_utf8_lossy(&output.stderr).trim().to_string()))
+}
+
+/// Runs a firewall command that is allowed to fail, like deleting something that may not
+/// be there, and tells whether it succeeded.
+fn probe(program: &str, args: &[String]) -> Result<bool, String> {
+    execute(program, args, None).map(|(success, _)| success)
+}
+
+/// Runs a firewall command that has to succeed.
+fn run(program: &str, args: &[String], stdin: Option<&str>) -> Result<(), String> {
+    let (success, stderr) = execute(program, args, stdin)?;
+    if success {
+        Ok(())
+    } else if stderr.is_empty() {
+        Err(format!("`{} {}` failed", program, args.join(" ")))
+    } else {
+        Err(format!("`{} {}` failed: {}", program, args.join(" "), stderr))
+    }
+}
+
+fn strings(args: &[&str]) -> Vec<String> {
+    args.iter().map(|a| a.to_string()).collect()
+}
+
+/// The chains of the nat table shredder hooks into.
+const IPTABLES_BUILTINS: [&str; 2] = ["OUTPUT", "POSTROUTING"];
+
+/// Rules in chains of their own in the nat table, through `iptables` or `iptables-nft`,
+/// and their IPv6 counterparts.
+pub struct Iptables {
+    binary: &'static str,
+    /// the binary for the IPv6 rules, `None` when the tun has no IPv6 address
+    binary6: Option<&'static str>,
+    /// the builtin chains and the chain of its own each one jumps to, named after the tun so
+    /// instances with different tuns don't touch each other's rules
+    chains: [(&'static str, String); 2],
+    torn_down: bool,
+}
+
+impl Iptables {
+    pub fn new(binary: &'static str, binary6: Option<&'static str>, name: &str) -> Iptables {
+        let chains = IPTABLES_BUILTINS.map(|builtin| (builtin, format!("{}-{}", name, builtin)));
+        Iptables { binary, binary6, chains, torn_down: false }
+    }
+
+    fn binaries(&self) -> Vec<&'static str> {
+        iter::once(self.binary).chain(self.binary6).collect()
+    }
+
+    fn binary_for(&self, rule: &NatRule) -> &'static str {
+        match self.binary6 {
+            Some(binary6) if rule.is_ipv6() => binary6,
+            _ => self.binary,
+        }
+    }
+
+    /// The chain of a rule and its match/target arguments.
+    fn render<'a>(&'a self, rule: &NatRule) -> (&'a str, Vec<String>) {
+        match rule {
+            NatRule::Dnat { dest, ports, phony } => {
+                // only tcp is rewritten, everything else goes out directly
+                let mut args = strings(&["-d", &dest.to_string(), "-p", "tcp"]);
+                if let Some(range) = ports {
+                    args.extend(["--dport".to_string(), format!("{}:{}", range.start, range.end)]);
+                }
+                // single addresses are DNATed, whole prefixes are NETMAPed onto their phony prefix
+                if dest.host_bits() == 0 {
+                    args.extend(["-j".to_string(), "DNAT".to_string(), "--to-destination".to_string(), phony.to_string()]);
+                } else {
+                    args.extend(["-j".to_string(), "NETMAP".to_string(), "--to".to_string(), phony.to_string()]);
+                }
+                (&self.chains[0].1, args)
+            },
+            NatRule::Snat { phony, origin } => {
+                (&self.chains[1].1, vec!["-s".to_string(), phony.to_string(), "-j".to_string(), "SNAT".to_string(), "--to-source".to_string(), origin.to_string()])
+            },
+        }
+    }
+
+    fn nat(binary: &str, args: &[String]) -> Result<(), String> {
+        let mut nat_args = strings(&["-t", "nat"]);
+        nat_args.extend(args.iter().cloned());
+        run(binary, &nat_args, None)
+    }
+
+    fn probe_nat(binary: &str, args: &[String]) -> Result<bool, String> {
+        let mut nat_args = strings(&["-t", "nat"]);
+        nat_args.extend(args.iter().cloned());
+        probe(binary, &nat_args)
+    }
+
+    fn apply(&self, action: &str, rule: &NatRule) -> Result<(), String> {
+        let (chain, rule_args) = self.render(rule);
+        let mut args = strings(&[action, chain]);
+        args.extend(rule_args);
+        Iptables::nat(self.binary_for(rule), &args)
+    }
+
+    /// Unhooks, flushes and deletes the chains of shredder, whatever is left of them.
+    fn remove_chains(&self) -> Result<(), String> {
+        for binary in self.binaries() {
+            for (builtin, chain) in self.chains.iter() {
+                // a crashed instance may have hooked the chain more than once
+                while Iptables::probe_nat(binary, &strings(&["-D", builtin, "-j", chain]))? {}
+                if Iptables::probe_nat(binary, &strings(&["-n", "-L", chain]))? {
+                    Iptables::nat(binary, &strings(&["-F", chain]))?;
+                    Iptables::nat(binary, &strings(&["-X", chain]))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Firewall for Iptables {
+    fn remove_stale(&mut self) -> Result<bool, String> {
+        let mut stale = false;
+        for binary in self.binaries() {
+            for (_, chain) in self.chains.iter() {
+                stale |= Iptables::probe_nat(binary, &strings(&["-n", "-L", chain]))?;
+            }
+        }
+        if stale {
+            self.remove_chains()?;
+        }
+        Ok(stale)
+    }
+
+    fn commands(&self, rules: &[NatRule]) -> Vec<String> {
+        let mut commands = Vec::new();
+        for binary in self.binaries() {
+            for (builtin, chain) in self.chains.iter() {
+                commands.push(format!("{} -t nat -N {}", binary, chain));
+                commands.push(format!("{} -t nat -I {} -j {}", binary, builtin, chain));
+            }
+        }
+        for rule in rules.iter() {
+            let (chain, args) = self.render(rule);
+            commands.push(format!("{} -t nat -A {} {}", self.binary_for(rule), chain, args.join(" ")));
+        }
+        commands
+    }
+
+    fn install(&mut self, rules: &[NatRule]) -> Result<(), String> {
+        let installed = self.binaries().into_iter()
+            .flat_map(|binary| self.chains.iter().map(move |chains| (binary, chains)))
+            .try_for_each(|(binary, (builtin, chain))| {
+                Iptables::nat(binary, &strings(&["-N", chain]))?;
+                Iptables::nat(binary, &strings(&["-I", builtin, "-j", chain]))
+            })
+            .and_then(|_| self.add(rules));
+
+        if installed.is_err() {
+            // leave nothing half configured behind
+            if let Err(m) = self.remove_chains() {
+                eprintln!("Error while rolling back the firewall rules: {}", m);
+            }
+        }
+        installed
+    }
+
+    /// Adds the rules in order, if one is rejected the ones before it are removed again.
+    fn add(&mut self, rules: &[NatRule]) -> Result<(), String> {
+        check_not_torn_down(self.torn_down)?;
+        for (pos, rule) in rules.iter().enumerate() {
+            if let Err(m) = self.apply("-A", rule) {
+                for added in rules[..pos].iter().rev() {
+                    if let Err(m) = self.apply("-D", added) {
+                        eprintln!("Error while rolling back the firewall rules: {}", m);
+                    }
+                }
+                return Err(m);
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes as many of the rules as possible, returns the first error.
+    fn remove(&mut self, rules: &[NatRule]) -> Result<(), String> {
+        check_not_torn_down(self.torn_down)?;
+        let mut result = Ok(());
+        for rule in rules.iter() {
+            if let Err(m) = self.apply("-D", rule) {
+                result = result.and(Err(m));
+            }
+        }
+        result
+    }
+
+    fn teardown(&mut self) -> Result<(), String> {
+        self.torn_down = true;
+        self.remove_chains()
+    }
+}
+
+/// Rules in an inet table of their own, for both IPv4 and IPv6, replaced as a whole in a
+/// single transaction on every change.
+pub struct Nftables {
+    /// named after the tun, like the iptables chains
+    table: String,
+    rules: Vec<NatRule>,
+    torn_down: bool,
+}
+
+impl Nftables {
+    pub fn new(name: &str) -> Nftables {
+        Nftables { table: name.to_string(), rules: Vec::new(), torn_down: false }
+    }
+
+    fn render(rule: &NatRule) -> String {
+        let family = if rule.is_ipv6() { "ip6" } else { "ip" };
+        match rule {
+            NatRule::Dnat { dest, ports, phony } => {
+                let ports = match ports {
+                    Some(range) if range.start == range.end => format!(" tcp dport {}", range.start),
+                    Some(range) => format!(" tcp dport {}-{}", range.start, range.end),
+                    None => " meta l4proto tcp".to_string(),
+                };
+                if dest.host_bits() == 0 {
+                    format!("{f} daddr {}{} dnat {f} to {}", dest, ports, phony, f = family)
+                } else {
+                    format!("{f} daddr {}{} dnat {f} prefix to {f} daddr map {{ {} : {} }}", dest, ports, dest, phony, f = family)
+                }
+            },
+            NatRule::Snat { phony, origin } => format!("{f} saddr {} snat {f} to {}", phony, origin, f = family),
+        }
+    }
+
+    /// A script that atomically replaces the contents of the table with `rules`.
+    fn script(&self, rules: &[NatRule]) -> String {
+        let mut output = String::new();
+        let mut postrouting = String::new();
+        for rule in rules.iter() {
+            match rule {
+                NatRule::Dnat { .. } => output.push_str(&format!("\t\t{}\n", Nftables::render(rule))),
+                NatRule::Snat { .. } => postrouting.push_str(&format!("\t\t{}\n", Nftables::render(rule))),
+            }
+        }
+
+        format!("table inet {table} {{}}\n\
+                 flush table inet {table}\n\
+                 table inet {table} {{\n\
+                 \tchain output {{\n\
+                 \t\ttype nat hook output priority -100; policy accept;\n\
+                 {output}\
+                 \t}}\n\
+                 \tchain postrouting {{\n\
+                 \t\ttype nat hook postrouting priority 100; policy accept;\n\
+                 {postrouting}\
+                 \t}}\n\
+                 }}\n",
+                table = self.table, output = output, postrouting = postrouting)
+    }
+
+    /// Replaces the table with `rules`, which become the current rules if nft accepts them.
+    /// The whole script is one transaction, so nothing changes if it doesn't.
+    fn sync(&mut self, rules: Vec<NatRule>) -> Result<(), String> {
+        run("nft", &strings(&["-f", "-"]), Some(&self.script(&rules)))?;
+        self.rules = rules;
+        Ok(())
+    }
+}
+
+impl Firewall for Nftables {
+    fn remove_stale(&mut self) -> Result<bool, String> {
+        // install replaces whatever is in the table anyway
+        probe("nft", &strings(&["list", "table", "inet", &self.table]))
+    }
+
+    fn commands(&self, rules: &[NatRule]) -> Vec<String> {
+        vec![format!("nft -f - <<EOF\n{}EOF", self.script(rules))]
+    }
+
+    fn install(&mut self, rules: &[NatRule]) -> Result<(), String> {
+        self.sync(rules.to_vec())
+    }
+
+    fn add(&mut self, rules: &[NatRule]) -> Result<(), String> {
+        check_not_torn_down(self.torn_down)?;
+        let mut updated = self.rules.clone();
+        updated.extend(rules.iter().cloned());
+        self.sync(updated)
+    }
+
+    fn remove(&mut self, rules: &[NatRule]) -> Result<(), String> {
+        check_not_torn_down(self.torn_down)?;
+        let mut updated = self.rules.clone();
+        for rule in rules.iter() {
+            if let Some(pos) = updated.iter().position(|r| r == rule) {
+                updated.remove(pos);
+            }
+        }
+        self.sync(updated)
+    }
+
+    fn teardown(&mut self) -> Result<(), String> {
+        self.torn_down = true;
+        self.rules.clear();
+        // declaring the table first makes deleting it succeed when it's already gone
+        let script = format!("table inet {table} {{}}\ndelete table inet {table}\n", table = self.table);
+        run("nft", &strings(&["-f", "-"]), Some(&script))
+    }
+}
@@ -0,0 +1,323 @@
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use pnet::packet::tcp::{TcpFlags, TcpPacket};
+
+use crate::configfile::Application;
+use crate::server::Route;
+
+const TCP_OPTION_END: u8 = 0;
+const TCP_OPTION_NOP: u8 = 1;
+const TCP_OPTION_SACK: u8 = 5;
+
+/// How long a closed flow is kept around for the retransmitted FINs and last ACKs.
+pub const CLOSED_FLOW_LINGER: Duration = Duration::from_secs(10);
+
+/// Identifies a tcp flow of an application, the same for both directions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    pub phony: IpAddr,
+    pub dest: IpAddr,
+    pub local_port: u16,
+    pub remote_port: u16,
+}
+
+impl FlowKey {
+    pub fn new(tcp: &TcpPacket, route: &Route) -> FlowKey {
+        let (local_port, remote_port) = if route.outbound {
+            (tcp.get_source(), tcp.get_destination())
+        } else {
+            (tcp.get_destination(), tcp.get_source())
+        };
+
+        FlowKey {
+            phony: route.phony,
+            dest: route.dest,
+            local_port,
+            remote_port,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowState {
+    /// only a SYN has been seen so far
+    Opening,
+    Established,
+    /// a FIN has been seen in one direction
+    Closing,
+    /// FINs have been seen in both directions, or a RST in any
+    Closed,
+}
+
+#[derive(Debug)]
+pub struct Flow {
+    pub state: FlowState,
+    pub last_seen: Instant,
+    fin_out: bool,
+    fin_in: bool,
+    /// sequence number of the ClientHello, once it has been shredded
+    pub hello_seq: Option<u32>,
+    /// the ClientHello payload as it was sent out, when it was rewritten
+    pub hello_payload: Option<Vec<u8>>,
+    /// how much longer the outbound payload got at `hello_seq`
+    pub seq_delta: u32,
+    /// outbound segments carrying data and their payload bytes, retransmissions included
+    pub out_packets: u64,
+    pub out_bytes: u64,
+}
+
+/// true if `a` comes after `b` in the (wrapping) sequence space
+fn seq_after(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+impl Flow {
+    fn new(now: Instant) -> Flow {
+        Flow {
+            state: FlowState::Opening,
+            last_seen: now,
+            fin_out: false,
+            fin_in: false,
+            hello_seq: None,
+            hello_payload: None,
+            seq_delta: 0,
+            out_packets: 0,
+            out_bytes: 0,
+        }
+    }
+
+    /// Counts an outbound segment with `len` bytes of payload and tells whether it is still
+    /// inside the shredding budget of `app`, everything after it is only readdressed.
+    ///
+    /// Without `shred_bytes` and `shred_packets` the budget ends with the ClientHello.
+    pub fn within_budget(&mut self, app: &Application, len: usize) -> bool {
+        if len == 0 {
+            return false;
+        }
+
+        let within = if app.shred_bytes.is_none() && app.shred_packets.is_none() {
+            self.hello_seq.is_none()
+        } else {
+            app.shred_packets.is_none_or(|limit| self.out_packets < limit)
+                && app.shred_bytes.is_none_or(|limit| self.out_bytes < limit)
+        };
+
+        self.out_packets += 1;
+        self.out_bytes += len as u64;
+        within
+    }
+
+    /// Maps the sequence number of an outbound segment to the sequence space the
+    /// destination sees.
+    pub fn translate_seq(&self, seq: u32) -> u32 {
+        match self.hello_seq {
+            Some(hello) if self.seq_delta != 0 && seq_after(seq, hello) => seq.wrapping_add(self.seq_delta),
+            _ => seq,
+        }
+    }
+
+    /// Maps the acknowledgement number of an inbound segment back to the sequence space
+    /// of the origin.
+    pub fn translate_ack(&self, ack: u32) -> u32 {
+        let hello = match self.hello_seq {
+            Some(s) if self.seq_delta != 0 && seq_after(ack, s) => s,
+            _ => { return ack; }
+        };
+
+        let rewritten_len = self.hello_payload.as_ref().map(|p| p.len()).unwrap_or(0);
+        if ack.wrapping_sub(hello) as usize >= rewritten_len {
+            ack.wrapping_sub(self.seq_delta)
+        } else {
+            // only part of the rewritten ClientHello got acked, ack none of it
+            hello
+        }
+    }
+
+    /// Maps the edges of the SACK blocks (D-SACK included) in the options of an inbound
+    /// segment back to the sequence space of the origin, like `translate_ack`.
+    pub fn translate_sack(&self, options: &mut [u8]) {
+        if self.seq_delta == 0 {
+            return;
+        }
+
+        let mut pos = 0;
+        while pos < options.len() {
+            let kind = options[pos];
+            if kind == TCP_OPTION_END {
+                break;
+            }
+            if kind == TCP_OPTION_NOP {
+                pos += 1;
+                continue;
+            }
+            let len = match options.get(pos + 1) {
+                Some(len) if *len >= 2 && pos + (*len as usize) <= options.len() => *len as usize,
+                _ => break,
+            };
+            if kind == TCP_OPTION_SACK {
+                for edge in options[pos + 2..pos + len].chunks_exact_mut(4) {
+                    let seq = u32::from_be_bytes([edge[0], edge[1], edge[2], edge[3]]);
+                    edge.copy_from_slice(&self.translate_ack(seq).to_be_bytes());
+                }
+            }
+            pos += len;
+        }
+    }
+
+    fn update(&mut self, flags: u8, outbound: bool, now: Instant) {
+        if self.state == FlowState::Closed && flags & TcpFlags::SYN != 0 && flags & TcpFlags::ACK == 0 {
+            // the ports are being reused for a new connection
+            *self = Flow::new(now);
+        }
+        self.last_seen = now;
+
+        if flags & TcpFlags::RST != 0 {
+            self.state = FlowState::Closed;
+            return;
+        }
+        if flags & TcpFlags::FIN != 0 {
+            if outbound {
+                self.fin_out = true;
+            } else {
+                self.fin_in = true;
+            }
+        }
+
+        if self.fin_out && self.fin_in {
+            self.state = FlowState::Closed;
+        } else if self.fin_out || self.fin_in {
+            self.state = FlowState::Closing;
+        } else if self.state == FlowState::Opening && flags & TcpFlags::ACK != 0 {
+            self.state = FlowState::Established;
+        }
+    }
+}
+
+/// Keeps the per-flow state needed to rewrite a connection consistently in both directions.
+pub struct FlowTable {
+    flows: HashMap<FlowKey, Flow>,
+    idle_timeout: Duration,
+    /// for the flows whose sequence numbers are translated, forgetting one of those breaks
+    /// the connection if it resumes
+    translated_timeout: Duration,
+}
+
+impl FlowTable {
+    pub fn new(idle_timeout: Duration, translated_timeout: Duration) -> FlowTable {
+        FlowTable {
+            flows: HashMap::new(),
+            idle_timeout,
+            translated_timeout,
+        }
+    }
+
+    /// Updates the flow `key` belongs to with a segment, creating it if it's new.
+    pub fn track(&mut self, key: FlowKey, tcp: &TcpPacket, outbound: bool) -> &mut Flow {
+        let now = Instant::now();
+        let flow = self.flows.entry(key).or_insert_with(|| Flow::new(now));
+        flow.update(tcp.get_flags(), outbound, now);
+        flow
+    }
+
+    pub fn get(&self, key: &FlowKey) -> Option<&Flow> {
+        self.flows.get(key)
+    }
+
+    pub fn get_mut(&mut self, key: &FlowKey) -> Option<&mut Flow> {
+        self.flows.get_mut(key)
+    }
+
+    /// Drops the flows that have been idle for too long and the closed ones that
+    /// have lingered long enough, returns how many were dropped.
+    pub fn expire(&mut self) -> usize {
+        let now = Instant::now();
+        let before = self.flows.len();
+        let (idle_timeout, translated_timeout) = (self.idle_timeout, self.translated_timeout);
+        self.flows.retain(|_, flow| {
+            let idle = now.duration_since(flow.last_seen);
+            match flow.state {
+                FlowState::Closed => idle < CLOSED_FLOW_LINGER,
+                _ if flow.seq_delta != 0 => idle < translated_timeout,
+                _ => idle < idle_timeout,
+            }
+        });
+        before - self.flows.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A flow whose ClientHello at `hello` got `delta` bytes longer than its `len` bytes.
+    fn translated(hello: u32, len: usize, delta: u32) -> Flow {
+        let mut flow = Flow::new(Instant::now());
+        flow.hello_seq = Some(hello);
+        flow.hello_payload = Some(vec![0; len + delta as usize]);
+        flow.seq_delta = delta;
+        flow
+    }
+
+    #[test]
+    fn shifts_what_comes_after_the_hello() {
+        let flow = translated(1000, 100, 10);
+        assert_eq!(flow.translate_seq(999), 999);
+        assert_eq!(flow.translate_seq(1000), 1000);
+        assert_eq!(flow.translate_seq(1100), 1110);
+        assert_eq!(flow.translate_ack(1000), 1000);
+        assert_eq!(flow.translate_ack(1110), 1100);
+        assert_eq!(flow.translate_ack(2010), 2000);
+    }
+
+    #[test]
+    fn partial_acks_ack_none_of_the_hello() {
+        let flow = translated(1000, 100, 10);
+        assert_eq!(flow.translate_ack(1001), 1000);
+        assert_eq!(flow.translate_ack(1109), 1000);
+    }
+
+    #[test]
+    fn wraps_around() {
+        let hello = u32::MAX - 50;
+        let flow = translated(hello, 100, 10);
+        let after = hello.wrapping_add(100);
+        assert_eq!(flow.translate_seq(after), after.wrapping_add(10));
+        assert_eq!(flow.translate_ack(after.wrapping_add(10)), after);
+        assert_eq!(flow.translate_ack(hello.wrapping_add(5)), hello);
+        assert_eq!(flow.translate_seq(hello.wrapping_sub(1)), hello.wrapping_sub(1));
+    }
+
+    #[test]
+    fn untranslated_flows() {
+        let flow = translated(1000, 100, 0);
+        assert_eq!(flow.translate_seq(5000), 5000);
+        assert_eq!(flow.translate_ack(1050), 1050);
+    }
+
+    #[test]
+    fn translates_sack_blocks() {
+        let flow = translated(1000, 100, 10);
+        let mut options = vec![TCP_OPTION_NOP, TCP_OPTION_NOP, TCP_OPTION_SACK, 18];
+        for edge in [1210u32, 1310, 1510, 1610] {
+            options.extend_from_slice(&edge.to_be_bytes());
+        }
+        options.extend_from_slice(&[TCP_OPTION_END, 0]);
+        flow.translate_sack(&mut options);
+
+        let edges: Vec<u32> = options[4..20].chunks(4).map(|e| u32::from_be_bytes([e[0], e[1], e[2], e[3]])).collect();
+        assert_eq!(edges, vec![1200, 1300, 1500, 1600]);
+    }
+
+    #[test]
+    fn survives_bogus_options() {
+        let flow = translated(1000, 100, 10);
+        for mut options in [vec![TCP_OPTION_SACK], vec![TCP_OPTION_SACK, 0], vec![TCP_OPTION_SACK, 40, 0, 0], vec![8, 1]] {
+            let before = options.clone();
+            flow.translate_sack(&mut options);
+            assert_eq!(options, before);
+        }
+    }
+}
@@ -8,33 +8,62 @@ extern crate signal_hook;
 extern crate mio;
 
 use std::env;
+use std::process::exit;
 use std::thread;
-use std::sync::{mpsc::channel, Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use std::sync::atomic::{AtomicBool, Ordering};
 
 use getopts::Matches;
+use mio::Waker;
 use signal_hook::iterator::Signals;
-use signal_hook::consts::TERM_SIGNALS;
+use signal_hook::consts::{TERM_SIGNALS, SIGHUP, SIGINT, SIGTERM, SIGQUIT};
 
+mod bench;
+mod buffer;
 mod cmd;
 mod configfile;
 mod threadpool;
+mod cidr;
 mod device;
-
+mod firewall;
+mod flow;
+mod origin;
+mod packet;
+mod reload;
+mod resolver;
+mod server;
+mod shred;
+mod tls;
+
+use crate::bench::run_bench;
 use crate::cmd::{parse_args};
-use crate::configfile::read_config_file;
-use crate::device::create_and_configure_device;
+use crate::configfile::{detect_origins, read_config_file, ConfigFile, ConfigSource};
+use crate::device::{create_and_configure_device, stop_and_clean_up_device};
+use crate::firewall::{config_rules, firewall_for};
+use crate::origin::spawn_origin_watcher;
+use crate::server::{poll_for_tun, serve_forever};
+use crate::reload::reload_config;
+use crate::resolver::{resolver_for, resolve_domains, spawn_resolver};
 use crate::threadpool::ThreadPool;
 
-fn handle_signals(r: Arc<AtomicBool>){
-    let mut signal = Signals::new(TERM_SIGNALS).expect("oops2");
+fn handle_signals<F>(r: Arc<AtomicBool>, waker: Waker, reload: F)
+where
+    F: Fn() + Send + 'static,
+{
+    let mut signal = Signals::new(TERM_SIGNALS.iter().chain(&[SIGHUP])).expect("oops2");
     thread::spawn(move ||{
         for sig in signal.forever(){
             match sig {
-                SIGINT => {
+                SIGINT | SIGTERM | SIGQUIT => {
                     println!("closing down");
                     r.store(false, Ordering::SeqCst);
-                    break;
+                    if let Err(e) = waker.wake() {
+                        eprintln!("Error while waking up the tun reader: {}", e);
+                    }
+                },
+                SIGHUP => {
+                    println!("reloading the applications");
+                    reload();
                 },
                 _ => unreachable!(),
             }
@@ -42,34 +71,135 @@ fn handle_signals(r: Arc<AtomicBool>){
     });
 }
 
-fn run_command(config_path: String) -> Result<(), String>{
-    let config = read_config_file(config_path)?;
+fn run_command(source: ConfigSource) -> Result<(), String>{
+    let mut config = read_config_file(&source)?;
+    detect_origins(&mut config)?;
+
+    let resolver = resolver_for(&config);
+    resolve_domains(&mut config, resolver.as_ref());
 
-    let pool = ThreadPool::new(config.num_threads, config.applications.len());
+    let pool = ThreadPool::new(config.num_threads, config.queue_depth, config.overflow_policy);
+    let (poll, waker) = poll_for_tun()?;
 
-    let mut dev = match create_and_configure_device(&config) {
+    let mut firewall = firewall_for(&config);
+    let dev = match create_and_configure_device(&config, firewall.as_mut()) {
         Ok(s)=>s,
         Err(m) => { return Err(format!("Error while starting tun interface: {}", m)); }
     };
 
+    let config = Arc::new(RwLock::new(config));
+    let firewall = Arc::new(Mutex::new(firewall));
+
     let running = Arc::new(AtomicBool::new(true));
     let r = Arc::clone(&running);
-    handle_signals(r);
+    let (reload_config_ref, reload_firewall) = (Arc::clone(&config), Arc::clone(&firewall));
+    handle_signals(r, waker, move || {
+        if let Err(m) = reload_config(&source, &reload_config_ref, &reload_firewall) {
+            eprintln!("Error while reloading, keeping the running config: {}", m);
+        }
+    });
+
+    // always running, a reload may add domain names
+    spawn_resolver(Arc::clone(&config), Arc::clone(&firewall), resolver, Arc::clone(&running));
+    spawn_origin_watcher(Arc::clone(&config), Arc::clone(&firewall), Arc::clone(&running));
+
+    let result = serve_forever(Arc::clone(&config), pool, dev, poll, Arc::clone(&running));
+    // also when the server stopped on an error, for the resolver and the origin watcher
+    running.store(false, Ordering::SeqCst);
 
-    serve_forever(&config, pool, dev, running)?;
+    stop_and_clean_up_device(firewall.lock().unwrap().as_mut());
 
-    stop_and_clean_up_device(config, dev);
+    result
 }
 
-fn perform_command(command: String, opts: Matches) -> Result<(), String> {
-    if command == "run" {
-        let mut config_path = String::from("./config.json");
-        if opts.opt_present("c"){
-            config_path = opts.opt_str("c").expect("Unexpected error");
+fn print_plan(config: &ConfigFile) {
+    println!("tun device {}: address {}, netmask {}, mtu {}", config.name, config.address, config.subnet_mask, config.mtu);
+    if let Some(address6) = config.address6 {
+        println!("tun device {}: IPv6 address {}/{}", config.name, address6, config.prefix6);
+    }
+
+    for app in config.applications.iter() {
+        println!();
+        if app.follows_origin {
+            println!("application {}: origin {} (following {})", app.name, app.origin.unwrap(), config.origin);
+        } else {
+            println!("application {}: origin {}", app.name, app.origin.unwrap());
+        }
+        if let Some(origin6) = app.origin6 {
+            match config.ipv6_origin() {
+                Some(source) if app.follows_origin6 => println!("    IPv6 origin {} (following {})", origin6, source),
+                _ => println!("    IPv6 origin {}", origin6),
+            }
+        }
+        for block in app.domains.iter() {
+            println!("    {} gets the phony addresses {}", block.name, block.phony);
+        }
+        for mapping in app.mappings.iter() {
+            println!("    {} -> {}", mapping.dest, mapping.phony);
         }
-        run_command(config_path)?;
-    } else {
-        return Err(format!("Unknown command \"{}\"", command));
+        if app.mappings.is_empty() {
+            println!("    nothing to intercept yet");
+        }
+    }
+
+    println!();
+    let firewall = firewall_for(config);
+    for command in firewall.commands(&config_rules(config)).iter() {
+        println!("{}", command);
+    }
+}
+
+fn plan_command(source: ConfigSource) -> Result<(), String>{
+    let mut config = read_config_file(&source)?;
+    detect_origins(&mut config)?;
+
+    let resolver = resolver_for(&config);
+    resolve_domains(&mut config, resolver.as_ref());
+
+    print_plan(&config);
+    Ok(())
+}
+
+fn check_command(source: ConfigSource) -> Result<(), String>{
+    let config = match read_config_file(&source) {
+        Ok(s) => s,
+        Err(m) => { return Err(format!("{} is not valid:\n{}", source.path, m)); }
+    };
+
+    let intercepted: usize = config.applications.iter().map(|app| app.dest.len()).sum();
+    println!("{} is valid: {} applications intercepting {} destinations", source.path, config.applications.len(), intercepted);
+    Ok(())
+}
+
+fn bench_command(source: ConfigSource, count: usize) -> Result<(), String>{
+    let mut config = read_config_file(&source)?;
+    detect_origins(&mut config)?;
+    run_bench(&config, count)
+}
+
+fn perform_command(command: String, opts: Matches) -> Result<(), String> {
+    let mut config_path = String::from("./config.json");
+    if opts.opt_present("c"){
+        config_path = opts.opt_str("c").expect("Unexpected error");
+    }
+    let source = ConfigSource {
+        path: config_path,
+        format: opts.opt_str("f"),
+        overrides: opts.opt_strs("s"),
+    };
+
+    match &command[..] {
+        "run" => run_command(source)?,
+        "plan" => plan_command(source)?,
+        "check" => check_command(source)?,
+        "bench" => {
+            let count = match opts.opt_str("n") {
+                Some(s) => s.parse().map_err(|_| format!("invalid number of packets \"{}\"", s))?,
+                None => 100000,
+            };
+            bench_command(source, count)?
+        },
+        _ => { return Err(format!("Unknown command \"{}\"", command)); }
     }
     Ok(())
 }
@@ -82,7 +212,7 @@ fn main() {
         Ok(s)=>s,
         Err(m) => {
             eprintln!("Error while parsing the arguments: {}", m);
-            return;
+            exit(2);
         }
     };
 
@@ -92,6 +222,7 @@ fn main() {
         Ok(_)=>{},
         Err(m) => {
             eprintln!("Error: {}", m);
+            exit(1);
         }
     }
 }
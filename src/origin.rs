@@ -0,0 +1,281 @@
+
+use std::fs;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use pnet::datalink;
+
+use crate::configfile::{Application, Origin, SharedConfig};
+use crate::firewall::{mapping_rules, NatRule, SharedFirewall};
+
+/// How often the origin is looked up again when no address change has been announced,
+/// in case an announcement got lost.
+const ORIGIN_RECHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The interface of the default route with the lowest metric, from `/proc/net/route`.
+fn default_route_interface() -> Result<String, String> {
+    let routes = fs::read_to_string("/proc/net/route").map_err(|e| format!("/proc/net/route: {}", e))?;
+
+    let mut best: Option<(u32, &str)> = None;
+    // Iface Destination Gateway Flags RefCnt Use Metric Mask ...
+    for line in routes.lines().skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 8 || fields[1] != "00000000" || fields[7] != "00000000" {
+            continue;
+        }
+        let up = u16::from_str_radix(fields[3], 16).map(|flags| flags & 1 != 0).unwrap_or(false);
+        let metric = fields[6].parse::<u32>().unwrap_or(u32::MAX);
+        if up && best.is_none_or(|(m, _)| metric < m) {
+            best = Some((metric, fields[0]));
+        }
+    }
+
+    best.map(|(_, name)| name.to_string()).ok_or("there is no default route".to_string())
+}
+
+/// The interface of the IPv6 default route with the lowest metric, from `/proc/net/ipv6_route`.
+fn default_route6_interface() -> Result<String, String> {
+    let routes = fs::read_to_string("/proc/net/ipv6_route").map_err(|e| format!("/proc/net/ipv6_route: {}", e))?;
+
+    let mut best: Option<(u32, &str)> = None;
+    // Destination PrefixLen Source PrefixLen NextHop Metric RefCnt Use Flags Iface
+    for line in routes.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 10 || fields[0].chars().any(|c| c != '0') || fields[1] != "00" {
+            continue;
+        }
+        // up and not a reject route, there's one of those on lo when there's no default route
+        let usable = u32::from_str_radix(fields[8], 16).map(|flags| flags & 1 != 0 && flags & 0x200 == 0).unwrap_or(false);
+        let metric = u32::from_str_radix(fields[5], 16).unwrap_or(u32::MAX);
+        if usable && best.is_none_or(|(m, _)| metric < m) {
+            best = Some((metric, fields[9]));
+        }
+    }
+
+    best.map(|(_, name)| name.to_string()).ok_or("there is no IPv6 default route".to_string())
+}
+
+fn interface_addresses(name: &str) -> Result<Vec<IpAddr>, String> {
+    datalink::interfaces().into_iter()
+        .find(|i| i.name == name)
+        .map(|i| i.ips.iter().map(|net| net.ip()).collect())
+        .ok_or(format!("there is no interface named {}", name))
+}
+
+/// The first IPv4 address of an interface.
+fn interface_address(name: &str) -> Result<Ipv4Addr, String> {
+    interface_addresses(name)?.into_iter()
+        .find_map(|addr| match addr {
+            IpAddr::V4(addr) => Some(addr),
+            IpAddr::V6(_) => None,
+        })
+        .ok_or(format!("{} has no IPv4 address", name))
+}
+
+/// The first IPv6 address of an interface that isn't link-local.
+fn interface_address6(name: &str) -> Result<Ipv6Addr, String> {
+    interface_addresses(name)?.into_iter()
+        .find_map(|addr| match addr {
+            IpAddr::V6(addr) if addr.segments()[0] & 0xffc0 != 0xfe80 => Some(addr),
+            _ => None,
+        })
+        .ok_or(format!("{} has no IPv6 address that isn't link-local", name))
+}
+
+/// The address `origin` stands for right now.
+pub fn detect_origin(origin: &Origin) -> Result<Ipv4Addr, String> {
+    match origin {
+        Origin::Address(IpAddr::V4(addr)) => Ok(*addr),
+        Origin::Address(addr) => Err(format!("{} is not an IPv4 address", addr)),
+        Origin::Interface(name) => interface_address(name),
+        Origin::DefaultRoute => interface_address(&default_route_interface()?),
+    }
+}
+
+/// The IPv6 address `origin` stands for right now.
+pub fn detect_origin6(origin: &Origin) -> Result<Ipv6Addr, String> {
+    match origin {
+        Origin::Address(IpAddr::V6(addr)) => Ok(*addr),
+        Origin::Address(addr) => Err(format!("{} is not an IPv6 address", addr)),
+        Origin::Interface(name) => interface_address6(name),
+        Origin::DefaultRoute => interface_address6(&default_route6_interface()?),
+    }
+}
+
+/// A netlink socket that hears about the address and route changes of the kernel.
+struct AddressEvents {
+    fd: OwnedFd,
+}
+
+impl AddressEvents {
+    fn open(timeout: Duration) -> io::Result<AddressEvents> {
+        let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, libc::NETLINK_ROUTE) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = (libc::RTMGRP_IPV4_IFADDR | libc::RTMGRP_IPV4_ROUTE | libc::RTMGRP_IPV6_IFADDR | libc::RTMGRP_IPV6_ROUTE) as u32;
+        let bound = unsafe {
+            libc::bind(fd.as_raw_fd(), &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                       mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t)
+        };
+        if bound < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // wake up now and then to see whether we should stop
+        let tv = libc::timeval { tv_sec: timeout.as_secs() as libc::time_t, tv_usec: 0 };
+        let set = unsafe {
+            libc::setsockopt(fd.as_raw_fd(), libc::SOL_SOCKET, libc::SO_RCVTIMEO,
+                             &tv as *const libc::timeval as *const libc::c_void,
+                             mem::size_of::<libc::timeval>() as libc::socklen_t)
+        };
+        if set < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(AddressEvents { fd })
+    }
+
+    /// Waits for a change to be announced, returns false if there was none before the timeout.
+    /// The messages themselves aren't looked at, the origin is simply looked up again.
+    fn wait(&self) -> io::Result<bool> {
+        let mut buffer = [0u8; 8192];
+        let n = unsafe { libc::recv(self.fd.as_raw_fd(), buffer.as_mut_ptr() as *mut libc::c_void, buffer.len(), 0) };
+        if n >= 0 {
+            return Ok(true);
+        }
+
+        let e = io::Error::last_os_error();
+        match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted => Ok(false),
+            // more changes than the socket could hold, that is a change too
+            _ if e.raw_os_error() == Some(libc::ENOBUFS) => Ok(true),
+            _ => Err(e),
+        }
+    }
+}
+
+/// The SNAT rules of `app` for the family of `addr`.
+fn snat_rules(app: &Application, addr: IpAddr) -> Vec<NatRule> {
+    app.mappings.iter()
+        .flat_map(|mapping| mapping_rules(app, mapping))
+        .filter(|rule| matches!(rule, NatRule::Snat { .. }) && rule.is_ipv6() == addr.is_ipv6())
+        .collect()
+}
+
+/// true if `app` follows the origin of the family of `addr` and isn't there yet.
+fn moves_to(app: &Application, addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(addr) => app.follows_origin && app.origin != Some(addr),
+        IpAddr::V6(addr) => app.follows_origin6 && app.origin6 != Some(addr),
+    }
+}
+
+fn set_origin(app: &mut Application, addr: IpAddr) {
+    match addr {
+        IpAddr::V4(addr) => app.origin = Some(addr),
+        IpAddr::V6(addr) => app.origin6 = Some(addr),
+    }
+}
+
+/// Moves the applications that follow the origin of the family of `addr` over to it,
+/// SNAT rules included.
+fn update_origin(config: &SharedConfig, firewall: &SharedFirewall, addr: IpAddr) {
+    let apps: Vec<Application> = config.read().unwrap().applications.iter()
+        .filter(|app| moves_to(app, addr))
+        .map(|app| Application::clone(app))
+        .collect();
+    if apps.is_empty() {
+        return;
+    }
+    println!("origin changed to {}", addr);
+
+    let stale: Vec<NatRule> = apps.iter().flat_map(|app| snat_rules(app, addr)).collect();
+    let fresh: Vec<NatRule> = apps.into_iter()
+        .flat_map(|mut app| {
+            set_origin(&mut app, addr);
+            snat_rules(&app, addr)
+        })
+        .collect();
+
+    {
+        let mut firewall = firewall.lock().unwrap();
+        if let Err(m) = firewall.remove(&stale) {
+            eprintln!("Error while removing the SNAT rules of the old origin: {}", m);
+        }
+        if let Err(m) = firewall.add(&fresh) {
+            eprintln!("Error while adding the SNAT rules of the new origin: {}", m);
+        }
+    }
+
+    for app in config.write().unwrap().applications.iter_mut().filter(|app| moves_to(app, addr)) {
+        set_origin(Arc::make_mut(app), addr);
+    }
+}
+
+/// Follows the address of the origin interface in the background, when it changes the
+/// applications that use it are moved over to the new address.
+pub fn spawn_origin_watcher(config: SharedConfig, firewall: SharedFirewall, running: Arc<AtomicBool>) {
+    let (origin, origin6) = {
+        let config = config.read().unwrap();
+        let followed = |origin: &Origin| !matches!(origin, Origin::Address(_));
+        (Some(config.origin.clone()).filter(followed), config.ipv6_origin().cloned().filter(followed))
+    };
+    if origin.is_none() && origin6.is_none() {
+        return;
+    }
+
+    thread::spawn(move || {
+        let events = match AddressEvents::open(Duration::from_secs(1)) {
+            Ok(s) => Some(s),
+            Err(e) => {
+                eprintln!("Can not listen for address changes, looking up the origin every {} seconds instead: {}",
+                          ORIGIN_RECHECK_INTERVAL.as_secs(), e);
+                None
+            }
+        };
+
+        let mut last_check = Instant::now();
+        while running.load(Ordering::SeqCst) {
+            let announced = match &events {
+                Some(events) => events.wait().unwrap_or_else(|e| {
+                    eprintln!("Error while listening for address changes: {}", e);
+                    thread::sleep(Duration::from_secs(1));
+                    false
+                }),
+                None => {
+                    thread::sleep(Duration::from_secs(1));
+                    false
+                }
+            };
+            if !announced && last_check.elapsed() < ORIGIN_RECHECK_INTERVAL {
+                continue;
+            }
+            last_check = Instant::now();
+
+            // the interface may be coming back, the old address stays until it does
+            if let Some(origin) = &origin {
+                match detect_origin(origin) {
+                    Ok(addr) => update_origin(&config, &firewall, IpAddr::V4(addr)),
+                    Err(m) => eprintln!("Can not look up the origin: {}", m),
+                }
+            }
+            if let Some(origin6) = &origin6 {
+                match detect_origin6(origin6) {
+                    Ok(addr) => update_origin(&config, &firewall, IpAddr::V6(addr)),
+                    Err(m) => eprintln!("Can not look up the IPv6 origin: {}", m),
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,377 @@
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv4::{self, Ipv4Flags, Ipv4Packet, MutableIpv4Packet};
+use pnet::packet::ipv6::{Ipv6Packet, MutableIpv6Packet};
+use pnet::packet::tcp::{self, MutableTcpPacket, TcpPacket};
+
+use crate::buffer::PacketBuffer;
+
+pub const IPV6_HEADER_LEN: usize = 40;
+pub const IPV6_FRAGMENT_HEADER_LEN: usize = 8;
+
+/// An IPv4 or IPv6 packet read from the tun, with the headers in front of the transport
+/// protocol already walked through.
+#[derive(Debug, Clone)]
+pub struct IpPacket {
+    data: PacketBuffer,
+    /// length of the ip header, IPv6 extension headers included, the transport header starts here
+    header_len: usize,
+    /// offset of the field holding the transport protocol, in the ip header or in the last
+    /// IPv6 extension header
+    next_header_at: usize,
+    protocol: IpNextHeaderProtocol,
+    /// a fragment other than the first, it doesn't start with the transport header
+    later_fragment: bool,
+    /// a fragment, the first one included
+    fragment: bool,
+}
+
+impl IpPacket {
+    /// Parses an IPv4 or IPv6 packet, `None` if it's neither.
+    pub fn new(data: PacketBuffer) -> Option<IpPacket> {
+        match data.first()? >> 4 {
+            4 => IpPacket::new_ipv4(data),
+            6 => IpPacket::new_ipv6(data),
+            _ => None,
+        }
+    }
+
+    fn new_ipv4(data: PacketBuffer) -> Option<IpPacket> {
+        let ip = Ipv4Packet::new(&data)?;
+        let header_len = ip.get_header_length() as usize * 4;
+        if header_len < 20 || header_len > data.len() {
+            return None;
+        }
+        let more_fragments = ip.get_flags() & Ipv4Flags::MoreFragments != 0;
+        let later_fragment = ip.get_fragment_offset() != 0;
+        let protocol = ip.get_next_level_protocol();
+
+        Some(IpPacket {
+            data,
+            header_len,
+            next_header_at: 9,
+            protocol,
+            later_fragment,
+            fragment: more_fragments || later_fragment,
+        })
+    }
+
+    /// Walks the extension headers up to the transport header.
+    fn new_ipv6(data: PacketBuffer) -> Option<IpPacket> {
+        if data.len() < IPV6_HEADER_LEN {
+            return None;
+        }
+
+        let mut next_header_at = 6;
+        let mut pos = IPV6_HEADER_LEN;
+        let mut later_fragment = false;
+        let mut fragment = false;
+        loop {
+            let next = data[next_header_at];
+            let len = match IpNextHeaderProtocol::new(next) {
+                IpNextHeaderProtocols::Hopopt | IpNextHeaderProtocols::Ipv6Route | IpNextHeaderProtocols::Ipv6Opts => {
+                    (*data.get(pos + 1)? as usize + 1) * 8
+                },
+                IpNextHeaderProtocols::Ipv6Frag => {
+                    let offset = u16::from_be_bytes([*data.get(pos + 2)?, *data.get(pos + 3)?]);
+                    later_fragment = offset >> 3 != 0;
+                    fragment = true;
+                    IPV6_FRAGMENT_HEADER_LEN
+                },
+                IpNextHeaderProtocols::Ah => (*data.get(pos + 1)? as usize + 2) * 4,
+                _ => break,
+            };
+            if pos + len > data.len() {
+                return None;
+            }
+            next_header_at = pos;
+            pos += len;
+        }
+
+        let protocol = IpNextHeaderProtocol::new(data[next_header_at]);
+        Some(IpPacket {
+            data,
+            header_len: pos,
+            next_header_at,
+            protocol,
+            later_fragment,
+            fragment,
+        })
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        self.data[0] >> 4 == 6
+    }
+
+    pub fn packet(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data.into_vec()
+    }
+
+    /// The ip header, IPv6 extension headers included.
+    pub fn header(&self) -> &[u8] {
+        &self.data[..self.header_len]
+    }
+
+    /// Whatever comes after the ip headers.
+    pub fn payload(&self) -> &[u8] {
+        &self.data[self.header_len..]
+    }
+
+    pub fn protocol(&self) -> IpNextHeaderProtocol {
+        self.protocol
+    }
+
+    pub fn is_fragment(&self) -> bool {
+        self.fragment
+    }
+
+    pub fn source(&self) -> IpAddr {
+        if self.is_ipv6() {
+            IpAddr::V6(Ipv6Packet::new(&self.data).unwrap().get_source())
+        } else {
+            IpAddr::V4(Ipv4Packet::new(&self.data).unwrap().get_source())
+        }
+    }
+
+    pub fn destination(&self) -> IpAddr {
+        if self.is_ipv6() {
+            IpAddr::V6(Ipv6Packet::new(&self.data).unwrap().get_destination())
+        } else {
+            IpAddr::V4(Ipv4Packet::new(&self.data).unwrap().get_destination())
+        }
+    }
+
+    /// Sets the source address, which has to be of the family of the packet.
+    pub fn set_source(&mut self, addr: IpAddr) {
+        match addr {
+            IpAddr::V4(addr) => MutableIpv4Packet::new(&mut self.data).unwrap().set_source(addr),
+            IpAddr::V6(addr) => MutableIpv6Packet::new(&mut self.data).unwrap().set_source(addr),
+        }
+    }
+
+    /// Sets the destination address, which has to be of the family of the packet.
+    pub fn set_destination(&mut self, addr: IpAddr) {
+        match addr {
+            IpAddr::V4(addr) => MutableIpv4Packet::new(&mut self.data).unwrap().set_destination(addr),
+            IpAddr::V6(addr) => MutableIpv6Packet::new(&mut self.data).unwrap().set_destination(addr),
+        }
+    }
+
+    /// The tcp segment, `None` for other protocols and for the fragments that don't carry
+    /// the tcp header.
+    pub fn tcp(&self) -> Option<TcpPacket<'_>> {
+        if self.protocol != IpNextHeaderProtocols::Tcp || self.later_fragment {
+            return None;
+        }
+        TcpPacket::new(self.payload())
+    }
+
+    pub fn tcp_mut(&mut self) -> Option<MutableTcpPacket<'_>> {
+        if self.protocol != IpNextHeaderProtocols::Tcp || self.later_fragment {
+            return None;
+        }
+        MutableTcpPacket::new(&mut self.data[self.header_len..])
+    }
+
+    /// The options of the tcp header, empty when there are none.
+    pub fn tcp_options_mut(&mut self) -> Option<&mut [u8]> {
+        let tcp = self.tcp()?;
+        let end = self.header_len + tcp.get_data_offset() as usize * 4;
+        if end > self.data.len() {
+            return None;
+        }
+        Some(&mut self.data[(self.header_len + 20).min(end)..end])
+    }
+
+    /// Recomputes the ip header checksum (IPv4 only has one) and the tcp checksum,
+    /// needed after the addresses have been rewritten.
+    pub fn fix_checksums(&mut self) {
+        let (src, dst) = (self.source(), self.destination());
+        // a fragment only carries a part of the segment, its checksum can't be computed here
+        if !self.fragment {
+            if let Some(mut tcp) = self.tcp_mut() {
+                let checksum = match (src, dst) {
+                    (IpAddr::V4(src), IpAddr::V4(dst)) => tcp::ipv4_checksum(&tcp.to_immutable(), &src, &dst),
+                    (IpAddr::V6(src), IpAddr::V6(dst)) => tcp::ipv6_checksum(&tcp.to_immutable(), &src, &dst),
+                    _ => unreachable!(),
+                };
+                tcp.set_checksum(checksum);
+            }
+        }
+
+        if !self.is_ipv6() {
+            let mut ip = MutableIpv4Packet::new(&mut self.data).unwrap();
+            let checksum = ipv4::checksum(&ip.to_immutable());
+            ip.set_checksum(checksum);
+        }
+    }
+
+    /// Sets the length fields of the ip header to the length of the packet.
+    fn fix_length(&mut self) {
+        let len = self.data.len();
+        if self.is_ipv6() {
+            MutableIpv6Packet::new(&mut self.data).unwrap().set_payload_length((len - IPV6_HEADER_LEN) as u16);
+        } else {
+            MutableIpv4Packet::new(&mut self.data).unwrap().set_total_length(len as u16);
+        }
+    }
+
+    /// Builds a copy of the packet with `payload` after the ip headers, the lengths adjusted
+    /// and the IPv4 identification moved on by `id_offset`. The checksums are left alone.
+    pub fn with_payload(&self, payload: &[u8], id_offset: u16) -> IpPacket {
+        let mut data = Vec::with_capacity(self.header_len + payload.len());
+        data.extend_from_slice(self.header());
+        data.extend_from_slice(payload);
+
+        let mut packet = IpPacket { data: PacketBuffer::from(data), ..*self };
+        packet.fix_length();
+        if !packet.is_ipv6() && id_offset != 0 {
+            let mut ip = MutableIpv4Packet::new(&mut packet.data).unwrap();
+            let id = ip.get_identification();
+            ip.set_identification(id.wrapping_add(id_offset));
+        }
+        packet
+    }
+
+    /// Builds an IPv6 fragment of the packet: its headers, a fragment header and `data`, which
+    /// starts `offset` bytes into the part after the headers.
+    pub fn ipv6_fragment(&self, data: &[u8], offset: usize, more: bool, identification: u32) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.header_len + IPV6_FRAGMENT_HEADER_LEN + data.len());
+        buf.extend_from_slice(self.header());
+        buf[self.next_header_at] = IpNextHeaderProtocols::Ipv6Frag.0;
+        buf.push(self.protocol.0);
+        buf.push(0);
+        buf.extend_from_slice(&(((offset / 8) as u16) << 3 | more as u16).to_be_bytes());
+        buf.extend_from_slice(&identification.to_be_bytes());
+        buf.extend_from_slice(data);
+
+        let len = buf.len();
+        MutableIpv6Packet::new(&mut buf).unwrap().set_payload_length((len - IPV6_HEADER_LEN) as u16);
+        buf
+    }
+
+    pub fn ipv4(&self) -> Option<Ipv4Packet<'_>> {
+        if self.is_ipv6() {
+            None
+        } else {
+            Ipv4Packet::new(&self.data)
+        }
+    }
+}
+
+/// The address of the family of `addr` out of a pair of them, one for each family.
+pub fn same_family(addr: &IpAddr, v4: Option<Ipv4Addr>, v6: Option<Ipv6Addr>) -> Option<IpAddr> {
+    if addr.is_ipv6() {
+        v6.map(IpAddr::V6)
+    } else {
+        v4.map(IpAddr::V4)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use pnet::packet::Packet;
+
+    use crate::bench::tcp_packet;
+
+    pub(crate) fn v6_segment(payload: &[u8]) -> Vec<u8> {
+        tcp_packet("2001:db8::1".parse().unwrap(), "2001:db8::2".parse().unwrap(), 40000, 443, 0x18, 1000, 0, payload)
+    }
+
+    /// Puts an extension header of `kind` with `body` (its bytes after the next header and
+    /// length fields) right after the fixed IPv6 header of `packet`.
+    pub(crate) fn with_extension(packet: &[u8], kind: u8, body: &[u8]) -> Vec<u8> {
+        let len = 2 + body.len();
+        let mut header = vec![packet[6], if kind == IpNextHeaderProtocols::Ipv6Frag.0 { 0 } else { (len / 8 - 1) as u8 }];
+        header.extend_from_slice(body);
+
+        let mut data = packet[..IPV6_HEADER_LEN].to_vec();
+        data[6] = kind;
+        data.extend_from_slice(&header);
+        data.extend_from_slice(&packet[IPV6_HEADER_LEN..]);
+        let payload_len = (data.len() - IPV6_HEADER_LEN) as u16;
+        data[4..6].copy_from_slice(&payload_len.to_be_bytes());
+        data
+    }
+
+    fn parse(data: Vec<u8>) -> Option<IpPacket> {
+        IpPacket::new(PacketBuffer::from(data))
+    }
+
+    #[test]
+    fn walks_extension_headers() {
+        let plain = v6_segment(b"hello");
+        let data = with_extension(&plain, IpNextHeaderProtocols::Ipv6Route.0, &[0; 14]);
+        let data = with_extension(&data, IpNextHeaderProtocols::Hopopt.0, &[1, 4, 0, 0, 0, 0]);
+        let packet = parse(data).unwrap();
+        assert!(packet.is_ipv6());
+        assert_eq!(packet.header().len(), IPV6_HEADER_LEN + 8 + 16);
+        assert_eq!(packet.protocol(), IpNextHeaderProtocols::Tcp);
+        assert!(!packet.is_fragment());
+        assert_eq!(packet.tcp().unwrap().payload(), b"hello");
+        assert_eq!(packet.source(), "2001:db8::1".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn tells_fragments_apart() {
+        let plain = v6_segment(b"hello");
+        // the first fragment: offset 0, more fragments
+        let first = parse(with_extension(&plain, IpNextHeaderProtocols::Ipv6Frag.0, &[0, 1, 0, 0, 0, 7])).unwrap();
+        assert!(first.is_fragment());
+        assert!(first.tcp().is_some());
+        // a later one doesn't start with the tcp header
+        let later = parse(with_extension(&plain, IpNextHeaderProtocols::Ipv6Frag.0, &[0, 8 << 3, 0, 0, 0, 7])).unwrap();
+        assert!(later.is_fragment());
+        assert!(later.tcp().is_none());
+    }
+
+    #[test]
+    fn rejects_truncated_headers() {
+        let plain = v6_segment(b"");
+        assert!(parse(plain[..IPV6_HEADER_LEN - 1].to_vec()).is_none());
+        let data = with_extension(&plain, IpNextHeaderProtocols::Ipv6Opts.0, &[1, 12, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(parse(data[..IPV6_HEADER_LEN + 10].to_vec()).is_none());
+        assert!(parse(data[..IPV6_HEADER_LEN + 1].to_vec()).is_none());
+        assert!(parse(vec![]).is_none());
+        assert!(parse(vec![0x50; 40]).is_none());
+    }
+
+    #[test]
+    fn replaces_the_payload() {
+        let packet = parse(with_extension(&v6_segment(b"hello"), IpNextHeaderProtocols::Hopopt.0, &[1, 4, 0, 0, 0, 0])).unwrap();
+        let replaced = packet.with_payload(b"0123456789", 0);
+        // only the payload length changed in the headers
+        assert_eq!(replaced.header()[6..], packet.header()[6..]);
+        assert_eq!(replaced.payload(), b"0123456789");
+        assert_eq!(Ipv6Packet::new(replaced.packet()).unwrap().get_payload_length() as usize, 8 + 10);
+
+        let v4 = parse(tcp_packet("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap(), 1, 2, 0x10, 0, 0, b"abc")).unwrap();
+        let replaced = v4.with_payload(b"x", 3);
+        assert_eq!(replaced.ipv4().unwrap().get_total_length(), 21);
+        assert_eq!(replaced.ipv4().unwrap().get_identification(), v4.ipv4().unwrap().get_identification().wrapping_add(3));
+    }
+
+    #[test]
+    fn builds_ipv6_fragments() {
+        let packet = parse(with_extension(&v6_segment(b"hello"), IpNextHeaderProtocols::Hopopt.0, &[1, 4, 0, 0, 0, 0])).unwrap();
+        let fragment = packet.ipv6_fragment(b"abcdefgh", 16, true, 0xdeadbeef);
+        // the hop-by-hop header now leads to the fragment header, which leads to tcp
+        assert_eq!(fragment[IPV6_HEADER_LEN], IpNextHeaderProtocols::Ipv6Frag.0);
+        let at = IPV6_HEADER_LEN + 8;
+        assert_eq!(fragment[at], IpNextHeaderProtocols::Tcp.0);
+        assert_eq!(u16::from_be_bytes([fragment[at + 2], fragment[at + 3]]), 2 << 3 | 1);
+        assert_eq!(&fragment[at + 4..at + 8], &0xdeadbeefu32.to_be_bytes());
+        assert_eq!(&fragment[at + 8..], b"abcdefgh");
+
+        let parsed = parse(fragment).unwrap();
+        assert!(parsed.is_fragment());
+        assert_eq!(Ipv6Packet::new(parsed.packet()).unwrap().get_payload_length() as usize, 8 + 8 + 8);
+    }
+}
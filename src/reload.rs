@@ -0,0 +1,130 @@
+use std::slice;
+use std::sync::Arc;
+
+
+use crate::configfile::{detect_origins, reread_config_file, Application, ConfigFile, ConfigSource, Origin, SharedConfig};
+use crate::firewall::{mapping_rules, NatRule, SharedFirewall};
+use crate::resolver::{refresh_domains, resolver_for};
+
+/// The fields that are only read at startup, changing them takes a restart.
+fn restart_fields(current: &ConfigFile, new: &ConfigFile) -> Vec<&'static str> {
+    let mut fields = Vec::new();
+    if current.name != new.name { fields.push("name"); }
+    if current.address != new.address { fields.push("address"); }
+    if current.subnet_mask != new.subnet_mask { fields.push("subnet_mask"); }
+    if current.address6 != new.address6 { fields.push("address6"); }
+    if current.prefix6 != new.prefix6 { fields.push("prefix6"); }
+    if current.mtu != new.mtu { fields.push("mtu"); }
+    if current.num_threads != new.num_threads { fields.push("num_threads"); }
+    if current.scheduling != new.scheduling { fields.push("scheduling"); }
+    if current.queue_depth != new.queue_depth { fields.push("queue_depth"); }
+    if current.overflow_policy != new.overflow_policy { fields.push("overflow_policy"); }
+    if current.flow_timeout != new.flow_timeout { fields.push("flow_timeout"); }
+    if current.translated_flow_timeout != new.translated_flow_timeout { fields.push("translated_flow_timeout"); }
+    if current.firewall_backend != new.firewall_backend { fields.push("firewall_backend"); }
+    if current.hosts_file != new.hosts_file { fields.push("hosts_file"); }
+    if current.resolve_interval != new.resolve_interval { fields.push("resolve_interval"); }
+    // a new address is fine, the interface being followed is set at startup
+    match (&current.origin, &new.origin) {
+        (Origin::Address(_), Origin::Address(_)) => {},
+        (c, n) if c != n => fields.push("origin"),
+        _ => {},
+    }
+    match (&current.origin6, &new.origin6) {
+        (Some(Origin::Address(_)), Some(Origin::Address(_))) => {},
+        (c, n) if c != n => fields.push("origin6"),
+        _ => {},
+    }
+    fields
+}
+
+/// true if `new`, freshly read and not resolved yet, is the same application as the running
+/// `current`, whose mappings also hold the addresses its domain names resolved to.
+fn unchanged(current: &Application, new: &Application) -> bool {
+    let mut current = current.clone();
+    let domains = current.domains.clone();
+    current.mappings.retain(|m| !domains.iter().any(|d| d.phony.covers(&m.phony)));
+    current == *new
+}
+
+fn rules_of(app: &Application) -> Vec<NatRule> {
+    app.mappings.iter().flat_map(|mapping| mapping_rules(app, mapping)).collect()
+}
+
+/// Reads the config again and brings the running applications in line with it. Applications
+/// that didn't change are left alone along with their flows, the others get their firewall
+/// rules replaced. Nothing changes if the new config is invalid, changes a field that takes
+/// a restart, or if its rules can't be installed.
+pub fn reload_config(source: &ConfigSource, config: &SharedConfig, firewall: &SharedFirewall) -> Result<(), String> {
+    let current = config.read().unwrap().applications.clone();
+    let mut new = reread_config_file(source, &current)?;
+    detect_origins(&mut new)?;
+
+    let fields = restart_fields(&config.read().unwrap(), &new);
+    if !fields.is_empty() {
+        return Err(format!("{} can not be changed without a restart", fields.join(", ")));
+    }
+
+    let resolver = resolver_for(&new);
+    let mut stale = Vec::new();
+    let mut fresh = Vec::new();
+    let mut kept = Vec::with_capacity(new.applications.len());
+    for app in new.applications.iter_mut() {
+        let app = Arc::make_mut(app);
+        let current = current.iter().find(|c| c.name == app.name);
+        let keep = current.is_some_and(|c| unchanged(c, app));
+        kept.push(keep);
+        if keep {
+            continue;
+        }
+
+        match current {
+            Some(c) => {
+                println!("{}: changed", app.name);
+                stale.extend(rules_of(c));
+            },
+            None => println!("{}: added", app.name),
+        }
+        refresh_domains(app, resolver.as_ref());
+        fresh.extend(rules_of(app));
+    }
+    for app in current.iter().filter(|c| !new.applications.iter().any(|app| app.name == c.name)) {
+        println!("{}: removed", app.name);
+        stale.extend(rules_of(app));
+    }
+
+    if kept.iter().all(|k| *k) && current.len() == new.applications.len() {
+        println!("nothing changed");
+        return Ok(());
+    }
+
+    {
+        let mut firewall = firewall.lock().unwrap();
+        // one by one, so a failure puts back exactly the rules that were already removed
+        for (pos, rule) in stale.iter().enumerate() {
+            if let Err(m) = firewall.remove(slice::from_ref(rule)) {
+                if let Err(m) = firewall.add(&stale[..pos]) {
+                    eprintln!("Error while restoring the previous firewall rules: {}", m);
+                }
+                return Err(m);
+            }
+        }
+        if let Err(m) = firewall.add(&fresh) {
+            if let Err(m) = firewall.add(&stale) {
+                eprintln!("Error while restoring the previous firewall rules: {}", m);
+            }
+            return Err(m);
+        }
+    }
+
+    // the resolver may have moved the mappings of the kept applications in the meantime
+    let mut config = config.write().unwrap();
+    let applications = new.applications.into_iter().zip(kept).map(|(app, kept)| {
+        match config.applications.iter().find(|c| kept && c.name == app.name) {
+            Some(c) => c.clone(),
+            None => app,
+        }
+    }).collect();
+    config.applications = applications;
+    Ok(())
+}
@@ -0,0 +1,186 @@
+
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, ToSocketAddrs};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::cidr::Cidr;
+use crate::configfile::{Application, ConfigFile, Mapping, SharedConfig};
+use crate::firewall::{mapping_rules, SharedFirewall};
+
+/// Turns the domain names in `Application::dest` into addresses.
+pub trait Resolver: Send {
+    fn resolve(&self, name: &str) -> Result<Vec<Ipv4Addr>, String>;
+}
+
+/// Resolves through the system resolver (`getaddrinfo`).
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, name: &str) -> Result<Vec<Ipv4Addr>, String> {
+        let addrs = (name, 0).to_socket_addrs().map_err(|e| e.to_string())?;
+        Ok(addrs.filter_map(|addr| match addr.ip() {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(_) => None,
+        }).collect())
+    }
+}
+
+/// Resolves from a file in the `/etc/hosts` format, read again on every lookup.
+pub struct HostsFileResolver {
+    pub path: String,
+}
+
+impl Resolver for HostsFileResolver {
+    fn resolve(&self, name: &str) -> Result<Vec<Ipv4Addr>, String> {
+        let contents = fs::read_to_string(&self.path).map_err(|e| format!("{}: {}", self.path, e))?;
+
+        let mut addrs = Vec::new();
+        for line in contents.lines() {
+            let line = line.split('#').next().unwrap();
+            let mut fields = line.split_whitespace();
+            let addr = match fields.next().map(|f| f.parse::<Ipv4Addr>()) {
+                Some(Ok(addr)) => addr,
+                _ => continue,
+            };
+            if fields.any(|host| host.trim_end_matches('.').eq_ignore_ascii_case(name)) {
+                addrs.push(addr);
+            }
+        }
+        Ok(addrs)
+    }
+}
+
+pub fn resolver_for(config: &ConfigFile) -> Box<dyn Resolver> {
+    match &config.hosts_file {
+        Some(path) => Box::new(HostsFileResolver { path: path.clone() }),
+        None => Box::new(SystemResolver),
+    }
+}
+
+/// Resolves the domain names of `app` again and updates its mappings, the addresses that
+/// are still there keep their phony address so their flows are not disturbed.
+///
+/// Returns the mappings that were added and removed. A name that fails to resolve keeps
+/// its previous addresses.
+pub fn refresh_domains(app: &mut Application, resolver: &dyn Resolver) -> (Vec<Mapping>, Vec<Mapping>) {
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+
+    for block in app.domains.iter() {
+        let mut resolved: Vec<IpAddr> = match resolver.resolve(&block.name) {
+            Ok(s) => s.into_iter().map(IpAddr::V4).collect(),
+            Err(m) => {
+                eprintln!("{}: could not resolve {}: {}", app.name, block.name, m);
+                continue;
+            }
+        };
+        resolved.sort_unstable();
+        resolved.dedup();
+        if resolved.len() as u128 > block.phony.size() {
+            eprintln!("{}: {} resolves to {} addresses, only intercepting {} of them",
+                      app.name, block.name, resolved.len(), block.phony.size());
+            resolved.truncate(block.phony.size() as usize);
+        }
+
+        let (current, others): (Vec<Mapping>, Vec<Mapping>) = app.mappings.iter()
+            .partition(|m| block.phony.contains(m.phony.addr));
+        let (kept, gone): (Vec<Mapping>, Vec<Mapping>) = current.into_iter()
+            .partition(|m| resolved.contains(&m.dest.addr));
+
+        let mut mappings = others;
+        mappings.extend(kept.iter().cloned());
+        // slots that were just freed go last, conntrack may still send their old flows there
+        let free = (0..block.phony.size())
+            .map(|offset| block.phony.nth(offset))
+            .filter(|phony| !kept.iter().any(|m| m.phony.addr == *phony));
+        let (recent, fresh): (Vec<IpAddr>, Vec<IpAddr>) = free
+            .partition(|phony| gone.iter().any(|m| m.phony.addr == *phony));
+        let mut slots = fresh.into_iter().chain(recent);
+        for addr in resolved.iter().filter(|addr| !kept.iter().any(|m| m.dest.addr == **addr)) {
+            let mapping = Mapping { dest: Cidr::host(*addr), phony: Cidr::host(slots.next().unwrap()) };
+            mappings.push(mapping);
+            added.push(mapping);
+        }
+
+        app.mappings = mappings;
+        removed.extend(gone);
+    }
+
+    (added, removed)
+}
+
+/// Resolves the domain names of every application, used at startup before any rules exist.
+pub fn resolve_domains(config: &mut ConfigFile, resolver: &dyn Resolver) {
+    for app in config.applications.iter_mut() {
+        refresh_domains(Arc::make_mut(app), resolver);
+    }
+}
+
+/// Re-resolves the domain names every `resolve_interval` seconds in the background, moving
+/// the firewall rules along with the addresses.
+pub fn spawn_resolver(config: SharedConfig, firewall: SharedFirewall, resolver: Box<dyn Resolver>, running: Arc<AtomicBool>) {
+    let interval = Duration::from_secs(config.read().unwrap().resolve_interval);
+    thread::spawn(move || {
+        let mut last = Instant::now();
+        while running.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_secs(1));
+            if last.elapsed() < interval {
+                continue;
+            }
+            last = Instant::now();
+
+            // resolve on a copy, lookups can take a while and the classifier needs the config
+            let mut changes = Vec::new();
+            let mut apps = config.read().unwrap().applications.clone();
+            for (pos, app) in apps.iter_mut().enumerate() {
+                let app = Arc::make_mut(app);
+                let (added, removed) = refresh_domains(app, resolver.as_ref());
+                if !added.is_empty() || !removed.is_empty() {
+                    changes.push((pos, app.clone(), added, removed));
+                }
+            }
+
+            {
+                // a reload may have replaced the application while it was being resolved
+                let mut config = config.write().unwrap();
+                changes.retain(|(pos, app, _, _)| {
+                    match config.applications.get_mut(*pos) {
+                        Some(current) if current.name == app.name && current.domains == app.domains => {
+                            Arc::make_mut(current).mappings = app.mappings.clone();
+                            true
+                        },
+                        _ => false,
+                    }
+                });
+            }
+
+            if changes.is_empty() {
+                continue;
+            }
+
+            let mut stale = Vec::new();
+            let mut fresh = Vec::new();
+            for (_, app, added, removed) in changes.iter() {
+                for mapping in removed.iter() {
+                    println!("{}: {} is gone", app.name, mapping.dest);
+                    stale.extend(mapping_rules(app, mapping));
+                }
+                for mapping in added.iter() {
+                    println!("{}: intercepting {} as {}", app.name, mapping.dest, mapping.phony);
+                    fresh.extend(mapping_rules(app, mapping));
+                }
+            }
+
+            let mut firewall = firewall.lock().unwrap();
+            if let Err(m) = firewall.remove(&stale) {
+                eprintln!("Error while removing the rules of stale addresses: {}", m);
+            }
+            if let Err(m) = firewall.add(&fresh) {
+                eprintln!("Error while adding the rules of new addresses: {}", m);
+            }
+        }
+    });
+}
@@ -1,67 +1,375 @@
-
-use std::env;
-use std::thread;
-use std::fs::File;
-use std::io::{self, Read, Write, ErrorKind};
-use std::net::{Ipv4Addr};
+use std::io::{Read, Write, ErrorKind};
+use std::os::fd::{AsRawFd, RawFd};
+use std::time::{Duration, Instant};
+use std::net::IpAddr;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{mpsc::channel, Arc, Mutex};
+use std::sync::{Arc, Mutex};
 use tun::platform::linux::Device;
-use pnet::packet::{Packet, ipv4::Ipv4Packet, ipv4::MutableIpv4Packet};
+use mio::{Events, Interest, Poll, Token, Waker};
+use mio::unix::SourceFd;
+use pnet::packet::Packet;
 use pnet::packet::ip::IpNextHeaderProtocols;
-use pnet::packet::tcp::{MutableTcpPacket};
-use pnet::util::MacAddr;
 
-mod threadpool;
+use crate::buffer::{BufferPool, PacketBuffer};
+use crate::configfile::{ConfigFile, Application, Scheduling, SharedConfig};
+use crate::threadpool::{class_of, ThreadPool};
+use crate::flow::{FlowKey, FlowTable};
+use crate::packet::{same_family, IpPacket};
+use crate::shred::{fixed_cuts, strategy_cuts, split_segment, split_records, replace_payload, fragment_packet};
+use crate::tls::is_client_hello;
+
+/// How often idle and closed flows are dropped from the flow table.
+const FLOW_EXPIRY_INTERVAL: Duration = Duration::from_secs(5);
+
+const TUN_TOKEN: Token = Token(0);
+const WAKE_TOKEN: Token = Token(1);
 
-use crate::threadpool::ThreadPool;
+/// What `serve_forever` waits on: the tun becoming readable, and a waker for shutting down.
+pub fn poll_for_tun() -> Result<(Poll, Waker), String> {
+    let poll = Poll::new().map_err(|e| format!("could not create a poll: {}", e))?;
+    let waker = Waker::new(poll.registry(), WAKE_TOKEN).map_err(|e| format!("could not create a waker: {}", e))?;
+    Ok((poll, waker))
+}
 
-pub fn serve_forever(config: &ConfigFile, pool: ThreadPool, dev: Device, running: AtomicBool) -> Result<(), String> {
+/// Makes `poll` announce (edge triggered) when the reader of the tun at `fd` has packets.
+pub fn register_tun(poll: &Poll, fd: RawFd) -> Result<(), String> {
+    poll.registry().register(&mut SourceFd(&fd), TUN_TOKEN, Interest::READABLE)
+        .map_err(|e| format!("could not poll the tun: {}", e))
+}
 
-    let mut buffer: Vec<u8> = vec![0u8, (config.mtu + 4) as usize];
-    let config = Arc::new(config);
+/// Waits up to `timeout` for the tun registered with `register_tun`, tells whether it has
+/// become readable.
+pub fn wait_for_tun(poll: &mut Poll, events: &mut Events, timeout: Duration) -> Result<bool, String> {
+    match poll.poll(events, Some(timeout)) {
+        Ok(()) => Ok(events.iter().any(|event| event.token() == TUN_TOKEN)),
+        Err(e) if e.kind() == ErrorKind::Interrupted => Ok(false),
+        Err(e) => Err(format!("Error while waiting for the tun: {}", e)),
+    }
+}
+
+/// Reads packets from the tun until `running` is cleared, waiting on `poll` (see `poll_for_tun`)
+/// whenever there is nothing to read. Wake its waker after clearing `running` to stop right away.
+pub fn serve_forever(config: SharedConfig, pool: ThreadPool, dev: Device, mut poll: Poll, running: Arc<AtomicBool>) -> Result<(), String> {
+
+    let (mtu, flow_timeout, translated_flow_timeout, scheduling, in_flight) = {
+        let config = config.read().unwrap();
+        (config.mtu, config.flow_timeout, config.translated_flow_timeout, config.scheduling, config.num_threads * config.queue_depth)
+    };
+    // enough buffers for every packet that can be waiting in the queues, and the ones being worked on
+    let buffers = BufferPool::new((mtu + 4) as usize, in_flight + 128);
     let (mut devr, devw) = dev.split();
+    register_tun(&poll, devr.as_raw_fd())?;
+    let mut events = Events::with_capacity(8);
+    // the readiness is edge triggered, so once it's announced the tun is read until it runs dry
+    let mut readable = true;
     let devw = Arc::new(Mutex::new(devw));
+    let flows = Arc::new(Mutex::new(FlowTable::new(Duration::from_secs(flow_timeout), Duration::from_secs(translated_flow_timeout))));
+    let mut last_expiry = Instant::now();
+    let mut dropped = 0;
+    // counted instead of reported one by one, there can be a lot of them
+    let mut ignored = 0;
     while running.load(Ordering::SeqCst) {
-        let n = match devr.read(buffer.as_mut_slice()) {
+        if last_expiry.elapsed() >= FLOW_EXPIRY_INTERVAL {
+            let expired = flows.lock().unwrap().expire();
+            if expired > 0 {
+                println!("expired {} flows", expired);
+            }
+            let total = pool.dropped();
+            if total > dropped {
+                println!("dropped {} packets on full queues, {} so far", total - dropped, total);
+                dropped = total;
+            }
+            if ignored > 0 {
+                eprintln!("ignored {} packets that aren't IP or don't belong to any application", ignored);
+                ignored = 0;
+            }
+            last_expiry = Instant::now();
+        }
+
+        if !readable {
+            // wake up for the flow expiry even when there's no traffic
+            readable = wait_for_tun(&mut poll, &mut events, FLOW_EXPIRY_INTERVAL)?;
+            continue;
+        }
+
+        let mut buffer = buffers.get();
+        let n = match devr.read(&mut buffer) {
             Ok(s) => s,
             Err(e) => {
-                if  e.kind() == ErrorKind::WouldBlock || 
-                    e.kind() == ErrorKind::TimedOut {
-                        thread::sleep(Duration::from_secs(1));
-                        continue;
+                if e.kind() == ErrorKind::WouldBlock {
+                    readable = false;
+                    continue;
+                } else if e.kind() == ErrorKind::Interrupted {
+                    continue;
                 } else {
-                    return Err(format!("Error while reading from tun: {}", e.to_string()));
+                    return Err(format!("Error while reading from tun: {}", e));
                 }
             }
         };
-        if n <= 0{
+        if n == 0 {
             return Err("Error n<=0".to_string());
         }
+        buffer.truncate(n);
 
-        let buf: Vec<u8> = buffer[0..n].iter().cloned().collect();
-        let mut packet = MutableIpv4Packet::owned(buf).unwrap();
+        // by name, the position of an application changes when a reload removes one before it
+        let (route, key, job, app_class) = {
+            let config = config.read().unwrap();
+            match prepare(&config, &flows, &devw, buffer) {
+                Some((route, key, job)) => (route, key, job, class_of(&config.applications[route.app].name)),
+                None => {
+                    ignored += 1;
+                    continue;
+                }
+            }
+        };
+        match (scheduling, key) {
+            (Scheduling::Application, _) => pool.schedule(job, app_class),
+            (Scheduling::Flow, Some(key)) => pool.schedule_by(job, &key),
+            // no ports to tell the flows apart, at least keep the packets between the same hosts together
+            (Scheduling::Flow, None) => pool.schedule_by(job, &(route.phony, route.dest)),
+        }
+    }
+    Ok(())
+}
+
+/// Works out where the packet read into `buffer` goes and tracks its flow, returning the job
+/// that rewrites the packet and writes it to `dev`, along with what it's scheduled by.
+/// `None` if it's neither IPv4 nor IPv6 or doesn't belong to any application.
+pub fn prepare<W: Write + Send + 'static>(config: &ConfigFile, flows: &Arc<Mutex<FlowTable>>, dev: &Arc<Mutex<W>>, buffer: PacketBuffer)
+                                         -> Option<(Route, Option<FlowKey>, impl FnOnce() + Send + 'static)> {
+    let packet = IpPacket::new(buffer)?;
+    let route = classify(config, &packet)?;
+    let app = Arc::clone(&config.applications[route.app]);
+
+    let mut shred = false;
+    let mut key = None;
+    if let Some(tcp) = packet.tcp() {
+        let flow_key = FlowKey::new(&tcp, &route);
+        let mut flows = flows.lock().unwrap();
+        let flow = flows.track(flow_key, &tcp, route.outbound);
+        shred = route.outbound && flow.within_budget(&app, tcp.payload().len());
+        key = Some(flow_key);
+    }
+
+    let dev = Arc::clone(dev);
+    let flows = Arc::clone(flows);
+    let mtu = config.mtu;
+    let job = move || {
+        process_packet(packet, dev, app, route, shred, flows, mtu);
+    };
+    Some((route, key, job))
+}
+
+/// Where a packet read from the tun goes.
+#[derive(Debug, Clone, Copy)]
+pub struct Route {
+    /// index of the application
+    pub app: usize,
+    pub outbound: bool,
+    /// the phony address of the flow, the destination of the packet in both directions
+    pub phony: IpAddr,
+    /// the real address of the remote end
+    pub dest: IpAddr,
+}
+
+/// Finds the application a packet belongs to, its direction and the addresses of its flow.
+fn classify(config: &ConfigFile, packet: &IpPacket) -> Option<Route> {
+    let ports = packet.tcp().map(|tcp| (tcp.get_source(), tcp.get_destination()));
 
-        let devwc = Arc::clone(devw);
+    let source = packet.source();
+    let phony = packet.destination();
+    for (pos, app) in config.applications.iter().enumerate() {
+        let origin = same_family(&source, app.origin, app.origin6);
+        let mut found = None;
+        for mapping in app.mappings.iter() {
+            if origin == Some(source) {
+                if let Some(offset) = mapping.phony.offset_of(phony) {
+                    found = Some((true, mapping.dest.nth(offset)));
+                    break;
+                }
+            } else if let Some(offset) = mapping.dest.offset_of(source) {
+                if mapping.phony.nth(offset) == phony {
+                    found = Some((false, source));
+                    break;
+                }
+            }
+        }
+
+        let (outbound, dest) = match found {
+            Some(s) => s,
+            None => continue,
+        };
 
-        for (pos, app) in config.applications.iter().enumerate() {
-            if app.origin.unwrap() == packet.get_source() && app.phony.unwrap() == packet.get_destination (){
-                pool.schedule(move || {
-                    process_packet(packet, devwc, app.clone(), true);
-                }, pos);
-            } else if app.phony.unwrap() == packet.get_destination() && app.dest == packet.get_source() {
-                pool.schedule(move || {
-                    process_packet(packet, devwc, app.clone(), false);
-                }, pos);
+        if let Some(ranges) = &app.ports {
+            // the remote port is the destination port on the way out and the source port on the way in
+            let remote_port = match ports {
+                Some((source, destination)) => if outbound { destination } else { source },
+                None => continue,
+            };
+            if !ranges.iter().any(|range| range.contains(remote_port)) {
+                continue;
             }
         }
 
-        eprintln!("Packet doesnt belong to any applications");
-        continue;
+        return Some(Route { app: pos, outbound, phony, dest });
     }
-    Ok(())
+
+    None
 }
 
-fn process_packet(packet: MutableTcpPacket, dev: Arc<Mutex<Device>>, target: Application, outbound: bool){
-    return;
+fn process_packet<W: Write>(mut packet: IpPacket, dev: Arc<Mutex<W>>, target: Arc<Application>, route: Route, shred: bool, flows: Arc<Mutex<FlowTable>>, mtu: u16){
+    let outbound = route.outbound;
+    if packet.protocol() != IpNextHeaderProtocols::Tcp {
+        return;
+    }
+
+    let key = match packet.tcp() {
+        Some(tcp) => FlowKey::new(&tcp, &route),
+        None => {
+            eprintln!("{}: packet is TCP but i couldnt parse it", target.name);
+            return;
+        }
+    };
+
+    let my_addr = same_family(&route.dest, target.origin, target.origin6).unwrap();
+    let phony_addr = route.phony;
+    let their_addr = route.dest;
+
+    if outbound {
+        packet.set_source(phony_addr);
+        packet.set_destination(their_addr);
+    } else {
+        packet.set_source(phony_addr);
+        packet.set_destination(my_addr);
+    }
+
+    let mut hello_handled = false;
+    let mut retransmission = false;
+    // the rewritten ClientHello when it is being retransmitted, it's written once the flow
+    // table is unlocked
+    let mut resend = None;
+    if let Some(flow) = flows.lock().unwrap().get(&key) {
+        let mut tcp = packet.tcp_mut().unwrap();
+        if outbound {
+            let seq = tcp.get_sequence();
+            if flow.hello_seq == Some(seq) {
+                resend = flow.hello_payload.clone();
+                retransmission = true;
+            } else {
+                hello_handled = flow.hello_seq.is_some();
+            }
+            tcp.set_sequence(flow.translate_seq(seq));
+        } else {
+            let ack = tcp.get_acknowledgement();
+            tcp.set_acknowledgement(flow.translate_ack(ack));
+            drop(tcp);
+            if let Some(options) = packet.tcp_options_mut() {
+                flow.translate_sack(options);
+            }
+        }
+    }
+
+    if let Some(payload) = resend {
+        write_record_split(&dev, &packet, &payload, &target, mtu);
+        return;
+    }
+
+    let hello = outbound && !hello_handled && match packet.tcp() {
+        Some(tcp) => is_client_hello(tcp.payload()),
+        None => false,
+    };
+
+    // without a budget only the ClientHello is shredded, with one every segment inside it is
+    let budgeted = target.shred_bytes.is_some() || target.shred_packets.is_some();
+    if !(shred || retransmission) || !(hello || budgeted) {
+        packet.fix_checksums();
+        write_packet(&dev, packet.packet());
+        return;
+    }
+
+    // the payload is only read where it sits in the packet's buffer
+    let (seq, cuts, payload_len, rewritten) = {
+        let tcp = packet.tcp().unwrap();
+        let cuts = strategy_cuts(&target.strategy, tcp.payload());
+        let rewritten = if hello && target.tls_record_split {
+            Some(split_records(tcp.payload(), &cuts))
+        } else {
+            None
+        };
+        (tcp.get_sequence(), cuts, tcp.payload().len(), rewritten)
+    };
+
+    if hello {
+        if let Some(flow) = flows.lock().unwrap().get_mut(&key) {
+            flow.hello_seq = Some(seq);
+            if let Some(rewritten) = &rewritten {
+                flow.seq_delta = (rewritten.len() - payload_len) as u32;
+                flow.hello_payload = Some(rewritten.clone());
+            }
+        }
+    }
+
+    if let Some(rewritten) = &rewritten {
+        write_record_split(&dev, &packet, rewritten, &target, mtu);
+        return;
+    }
+
+    if let Some(frag) = &target.ip_fragmentation {
+        packet.fix_checksums();
+        for fragment in fragment_packet(&packet, frag).iter() {
+            write_packet(&dev, fragment);
+        }
+        return;
+    }
+
+    match split_segment(&packet, &cuts) {
+        Ok(pieces) => {
+            for piece in pieces.iter() {
+                write_packet(&dev, piece);
+            }
+        },
+        Err(m) => {
+            eprintln!("{}: {}", target.name, m);
+        }
+    }
+}
+
+/// Sends `packet` with its payload replaced by the record split ClientHello in `payload`.
+///
+/// The new payload goes in a single segment unless the segment would no longer fit in the
+/// `mtu`, then it is cut into as many segments as it takes.
+fn write_record_split<W: Write>(dev: &Arc<Mutex<W>>, packet: &IpPacket, payload: &[u8], target: &Application, mtu: u16) {
+    let rewritten = match replace_payload(packet, payload) {
+        Ok(s) => s,
+        Err(m) => {
+            eprintln!("{}: {}", target.name, m);
+            return;
+        }
+    };
+
+    let pieces = match &target.ip_fragmentation {
+        Some(frag) => Ok(fragment_packet(&rewritten, frag)),
+        None => {
+            let headers_len = rewritten.packet().len() - payload.len();
+            let segment_size = (mtu as usize).saturating_sub(headers_len).max(1);
+            split_segment(&rewritten, &fixed_cuts(payload.len(), segment_size))
+        },
+    };
+
+    match pieces {
+        Ok(pieces) => {
+            for piece in pieces.iter() {
+                write_packet(dev, piece);
+            }
+        },
+        Err(m) => {
+            eprintln!("{}: {}", target.name, m);
+        }
+    }
+}
+
+fn write_packet<W: Write>(dev: &Arc<Mutex<W>>, packet: &[u8]) {
+    if let Err(e) = dev.lock().unwrap().write_all(packet) {
+        eprintln!("Error while writing to tun: {}", e);
+    }
 }
@@ -0,0 +1,392 @@
+
+use pnet::packet::Packet;
+use pnet::packet::ipv4::{self, Ipv4Flags, MutableIpv4Packet};
+use pnet::packet::tcp::TcpFlags;
+use rand::Rng;
+
+use crate::configfile::{Strategy, SniPosition, IpFragmentation};
+use crate::packet::IpPacket;
+use crate::tls::{ClientHello, RECORD_HEADER_LEN};
+
+/// Size of the pieces the ClientHello is chopped into.
+pub const DEFAULT_CHUNK_SIZE: usize = 16;
+/// Size of the ip payload carried by each fragment in ip fragmentation mode.
+pub const DEFAULT_FRAGMENT_SIZE: usize = 32;
+
+/// Returns the offsets at which a payload of `len` bytes is cut into pieces of `chunk_size` bytes.
+pub fn fixed_cuts(len: usize, chunk_size: usize) -> Vec<usize> {
+    (chunk_size..len).step_by(chunk_size.max(1)).collect()
+}
+
+/// Returns the offsets at which a payload of `len` bytes is cut into pieces with random
+/// sizes between `min_size` and `max_size`.
+pub fn random_cuts(len: usize, min_size: usize, max_size: usize) -> Vec<usize> {
+    let mut rng = rand::thread_rng();
+    let mut cuts = Vec::new();
+    let mut pos = rng.gen_range(min_size..=max_size);
+    while pos < len {
+        cuts.push(pos);
+        pos += rng.gen_range(min_size..=max_size);
+    }
+    cuts
+}
+
+/// Returns the offsets at which the ClientHello in `payload` is cut according to `strategy`.
+pub fn strategy_cuts(strategy: &Strategy, payload: &[u8]) -> Vec<usize> {
+    match *strategy {
+        Strategy::Fixed { chunk_size } => fixed_cuts(payload.len(), chunk_size),
+        Strategy::Sni { position } => match sni_cuts(payload, position) {
+            Some(s) => s,
+            None => fixed_cuts(payload.len(), DEFAULT_CHUNK_SIZE),
+        },
+        Strategy::Random { min_size, max_size } => random_cuts(payload.len(), min_size, max_size),
+        Strategy::Pieces { count } => fixed_cuts(payload.len(), payload.len().div_ceil(count)),
+    }
+}
+
+/// Returns the offsets of the cuts around the SNI of the ClientHello in `payload`,
+/// or `None` if it doesn't carry one.
+pub fn sni_cuts(payload: &[u8], position: SniPosition) -> Option<Vec<usize>> {
+    let hello = ClientHello::parse(payload)?;
+    let hostname = hello.hostname?;
+
+    let cuts = match position {
+        SniPosition::BeforeExtension => vec![hello.sni_extension?],
+        SniPosition::MidHostname => vec![hostname.start + hostname.len() / 2],
+        SniPosition::EachLabel => {
+            let dots: Vec<usize> = payload[hostname.clone()].iter().enumerate()
+                .filter(|&(_, &c)| c == b'.')
+                .map(|(i, _)| hostname.start + i)
+                .collect();
+            if dots.is_empty() {
+                vec![hostname.start + hostname.len() / 2]
+            } else {
+                dots
+            }
+        },
+    };
+
+    Some(cuts)
+}
+
+/// Rewrites the TLS record at the start of `payload` into several records, with the
+/// record boundaries at the offsets in `cuts`.
+///
+/// Only cuts inside the part of the record carried by `payload` are used, the last record
+/// also covers whatever of the original record comes in later segments. Every extra record
+/// makes the payload `RECORD_HEADER_LEN` bytes longer.
+pub fn split_records(payload: &[u8], cuts: &[usize]) -> Vec<u8> {
+    let record_len = u16::from_be_bytes([payload[3], payload[4]]) as usize;
+    let record_end = RECORD_HEADER_LEN + record_len;
+    let available = record_end.min(payload.len());
+
+    let mut bounds: Vec<usize> = cuts.iter().cloned().filter(|&c| c > RECORD_HEADER_LEN && c < available).collect();
+    bounds.sort_unstable();
+    bounds.dedup();
+    bounds.insert(0, RECORD_HEADER_LEN);
+    bounds.push(record_end);
+
+    let mut out = Vec::with_capacity(payload.len() + (bounds.len() - 2) * RECORD_HEADER_LEN);
+    for piece in bounds.windows(2) {
+        let (start, end) = (piece[0], piece[1]);
+        out.extend_from_slice(&payload[..3]);
+        out.extend_from_slice(&((end - start) as u16).to_be_bytes());
+        out.extend_from_slice(&payload[start..end.min(available)]);
+    }
+    if record_end < payload.len() {
+        out.extend_from_slice(&payload[record_end..]);
+    }
+    out
+}
+
+/// Builds a copy of `packet` carrying `payload` as its tcp payload, with the lengths and
+/// checksums adjusted.
+pub fn replace_payload(packet: &IpPacket, payload: &[u8]) -> Result<IpPacket, String> {
+    let tcp_header_len = match packet.tcp() {
+        Some(tcp) => tcp.get_data_offset() as usize * 4,
+        None => { return Err("packet is TCP but i couldnt parse it".to_string()); }
+    };
+
+    let mut segment = Vec::with_capacity(tcp_header_len + payload.len());
+    segment.extend_from_slice(&packet.payload()[..tcp_header_len]);
+    segment.extend_from_slice(payload);
+
+    let mut rewritten = packet.with_payload(&segment, 0);
+    rewritten.fix_checksums();
+    Ok(rewritten)
+}
+
+/// Splits the tcp segment carried by `packet` into several ip packets,
+/// one for each piece of the payload between the offsets in `cuts`.
+///
+/// Every piece gets a copy of the original ip and tcp headers (options included) with
+/// the lengths, sequence number and checksums adjusted. PSH and FIN are only kept on
+/// the last piece.
+pub fn split_segment(packet: &IpPacket, cuts: &[usize]) -> Result<Vec<Vec<u8>>, String> {
+    let tcp = match packet.tcp() {
+        Some(s) => s,
+        None => { return Err("packet is TCP but i couldnt parse it".to_string()); }
+    };
+    let tcp_header_len = tcp.get_data_offset() as usize * 4;
+    let payload = tcp.payload();
+
+    let mut bounds: Vec<usize> = cuts.iter().cloned().filter(|&c| c > 0 && c < payload.len()).collect();
+    bounds.sort_unstable();
+    bounds.dedup();
+    bounds.insert(0, 0);
+    bounds.push(payload.len());
+
+    let tcp_header = &packet.payload()[..tcp_header_len];
+    let last_flags = tcp.get_flags();
+    let middle_flags = last_flags & !(TcpFlags::PSH | TcpFlags::FIN);
+
+    let mut pieces = Vec::with_capacity(bounds.len() - 1);
+    for (i, piece) in bounds.windows(2).enumerate() {
+        let (start, end) = (piece[0], piece[1]);
+
+        let mut segment = Vec::with_capacity(tcp_header_len + end - start);
+        segment.extend_from_slice(tcp_header);
+        segment.extend_from_slice(&payload[start..end]);
+
+        let mut ip = packet.with_payload(&segment, i as u16);
+        {
+            let mut seg = ip.tcp_mut().unwrap();
+            seg.set_sequence(tcp.get_sequence().wrapping_add(start as u32));
+            seg.set_flags(if end == payload.len() { last_flags } else { middle_flags });
+        }
+        ip.fix_checksums();
+
+        pieces.push(ip.into_bytes());
+    }
+
+    Ok(pieces)
+}
+
+/// Splits `packet` into ip fragments of `frag.fragment_size` bytes of payload each, IPv6
+/// packets get a fragment header.
+///
+/// The checksums of `packet` must already be correct, the tcp header is carried
+/// untouched in the first fragment. The first IPv6 fragment holds all of the tcp header,
+/// receivers drop it otherwise (RFC 8200), so it can be larger than `frag.fragment_size`.
+pub fn fragment_packet(packet: &IpPacket, frag: &IpFragmentation) -> Vec<Vec<u8>> {
+    let ranges = |len: usize, first: usize| {
+        let mut ranges = Vec::new();
+        let mut start = 0;
+        while start < len {
+            let size = if start == 0 { frag.fragment_size.max(first) } else { frag.fragment_size };
+            let end = (start + size).min(len);
+            ranges.push((start, end));
+            start = end;
+        }
+        ranges
+    };
+
+    match packet.ipv4() {
+        Some(ip) => {
+            let data = ip.payload();
+            let base_offset = ip.get_fragment_offset() as usize * 8;
+            let more_fragments = ip.get_flags() & Ipv4Flags::MoreFragments != 0;
+
+            ranges(data.len(), 0).into_iter().map(|(from, end)| {
+                let mut buf = packet.with_payload(&data[from..end], 0).into_bytes();
+                let mut ip = MutableIpv4Packet::new(&mut buf[..]).unwrap();
+                ip.set_fragment_offset(((base_offset + from) / 8) as u16);
+                ip.set_flags(if end < data.len() || more_fragments { Ipv4Flags::MoreFragments } else { 0 });
+                let checksum = ipv4::checksum(&ip.to_immutable());
+                ip.set_checksum(checksum);
+                buf
+            }).collect::<Vec<Vec<u8>>>()
+        },
+        // already a fragment, it can't get a second fragment header
+        None if packet.is_fragment() => vec![packet.packet().to_vec()],
+        None => {
+            let data = packet.payload();
+            let identification = rand::thread_rng().gen::<u32>();
+            let tcp_header_len = packet.tcp().map(|tcp| tcp.get_data_offset() as usize * 4).unwrap_or(0);
+            ranges(data.len(), tcp_header_len.next_multiple_of(8)).into_iter()
+                .map(|(from, end)| packet.ipv6_fragment(&data[from..end], from, end < data.len(), identification))
+                .collect()
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer::PacketBuffer;
+    use pnet::packet::ip::IpNextHeaderProtocols;
+
+    use crate::bench::{client_hello, tcp_packet};
+    use crate::packet::IPV6_FRAGMENT_HEADER_LEN;
+    use crate::packet::IPV6_HEADER_LEN;
+    use crate::packet::tests::{v6_segment, with_extension};
+
+    /// An IPv4 tcp segment from 10.0.0.1:40000 to 10.0.0.2:443.
+    fn segment(seq: u32, flags: u8, payload: &[u8]) -> IpPacket {
+        let data = tcp_packet("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap(), 40000, 443, flags, seq, 0, payload);
+        IpPacket::new(PacketBuffer::from(data)).unwrap()
+    }
+
+    /// The records in `payload` as (header bytes, body).
+    fn records(mut payload: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut records = Vec::new();
+        while payload.len() >= RECORD_HEADER_LEN {
+            let len = u16::from_be_bytes([payload[3], payload[4]]) as usize;
+            let end = (RECORD_HEADER_LEN + len).min(payload.len());
+            records.push((payload[..3].to_vec(), payload[RECORD_HEADER_LEN..end].to_vec()));
+            payload = &payload[end..];
+        }
+        records
+    }
+
+    #[test]
+    fn record_split_lengths() {
+        let hello = client_hello("example.com");
+        let split = split_records(&hello, &[10, 20, 20, 3, hello.len() + 5]);
+        // two usable cuts, two extra records
+        assert_eq!(split.len() - hello.len(), 2 * RECORD_HEADER_LEN);
+
+        let records = records(&split);
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].1.len(), 5);
+        assert_eq!(records[1].1.len(), 10);
+        assert!(records.iter().all(|(header, _)| header[..] == hello[..3]));
+        let body: Vec<u8> = records.iter().flat_map(|(_, body)| body.clone()).collect();
+        assert_eq!(body, hello[RECORD_HEADER_LEN..]);
+    }
+
+    #[test]
+    fn record_split_of_a_partial_hello() {
+        let hello = client_hello("example.com");
+        let carried = &hello[..40];
+        let split = split_records(carried, &[20, 60]);
+        assert_eq!(split.len(), carried.len() + RECORD_HEADER_LEN);
+        // the last record still claims the rest of the ClientHello, sent in later segments
+        let last_len = u16::from_be_bytes([split[20 + 3], split[20 + 4]]) as usize;
+        assert_eq!(last_len, hello.len() - 20);
+        assert_eq!(&split[25..], &hello[20..40]);
+    }
+
+    #[test]
+    fn record_split_keeps_what_follows_the_record() {
+        let mut payload = client_hello("example.com");
+        let record_end = payload.len();
+        payload.extend_from_slice(&[0x17, 0x03, 0x03, 0, 1, 0xaa]);
+        let split = split_records(&payload, &[30]);
+        assert_eq!(split.len(), payload.len() + RECORD_HEADER_LEN);
+        assert_eq!(&split[split.len() - 6..], &payload[record_end..]);
+    }
+
+    #[test]
+    fn segment_split() {
+        let payload: Vec<u8> = (0..10).collect();
+        let packet = segment(u32::MAX - 4, TcpFlags::PSH | TcpFlags::ACK, &payload);
+        let pieces = split_segment(&packet, &[7, 3, 0, 10, 3]).unwrap();
+        assert_eq!(pieces.len(), 3);
+
+        let mut carried = Vec::new();
+        for (piece, (start, end)) in pieces.into_iter().zip([(0, 3), (3, 7), (7, 10)]) {
+            let piece = IpPacket::new(PacketBuffer::from(piece)).unwrap();
+            let ip = piece.ipv4().unwrap();
+            assert_eq!(ip.get_total_length() as usize, 40 + end - start);
+            assert_eq!(ip.get_checksum(), ipv4::checksum(&ip));
+            drop(ip);
+
+            let tcp = piece.tcp().unwrap();
+            assert_eq!(tcp.get_sequence(), (u32::MAX - 4).wrapping_add(start as u32));
+            assert_eq!(tcp.get_flags() & TcpFlags::PSH != 0, end == 10);
+            assert_eq!(tcp.get_flags() & TcpFlags::ACK, TcpFlags::ACK);
+            assert_eq!(tcp.get_checksum(), pnet::packet::tcp::ipv4_checksum(&tcp, &"10.0.0.1".parse().unwrap(), &"10.0.0.2".parse().unwrap()));
+            carried.extend_from_slice(tcp.payload());
+        }
+        assert_eq!(carried, payload);
+    }
+
+    #[test]
+    fn segment_replaced_payload() {
+        let hello = client_hello("example.com");
+        let packet = segment(1000, TcpFlags::PSH | TcpFlags::ACK, &hello);
+        let split = split_records(&hello, &[20]);
+        let rewritten = replace_payload(&packet, &split).unwrap();
+        assert_eq!(rewritten.ipv4().unwrap().get_total_length() as usize, 40 + split.len());
+        assert_eq!(rewritten.tcp().unwrap().payload(), &split[..]);
+        assert_eq!(rewritten.tcp().unwrap().get_sequence(), 1000);
+    }
+
+    /// The (offset, more fragments, identification, payload) of IPv6 fragments.
+    fn ipv6_fragments(fragments: &[Vec<u8>]) -> Vec<(usize, bool, u32, Vec<u8>)> {
+        fragments.iter().map(|fragment| {
+            let packet = IpPacket::new(PacketBuffer::from(fragment.clone())).unwrap();
+            // the fragment header is the last one before the payload
+            let at = packet.header().len() - IPV6_FRAGMENT_HEADER_LEN;
+            let header = &packet.header()[at..];
+            assert_eq!(header[0], IpNextHeaderProtocols::Tcp.0);
+            let field = u16::from_be_bytes([header[2], header[3]]);
+            let identification = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+            ((field >> 3) as usize * 8, field & 1 != 0, identification, packet.payload().to_vec())
+        }).collect()
+    }
+
+    #[test]
+    fn ipv4_fragments() {
+        let payload: Vec<u8> = (0..50).collect();
+        let packet = segment(1000, TcpFlags::PSH | TcpFlags::ACK, &payload);
+        let fragments = fragment_packet(&packet, &IpFragmentation { fragment_size: 32 });
+        assert_eq!(fragments.len(), 3);
+
+        let mut carried = Vec::new();
+        for (i, fragment) in fragments.iter().enumerate() {
+            let ip = pnet::packet::ipv4::Ipv4Packet::new(fragment).unwrap();
+            assert_eq!(ip.get_fragment_offset() as usize * 8, i * 32);
+            assert_eq!(ip.get_flags() & Ipv4Flags::MoreFragments != 0, i < 2);
+            assert_eq!(ip.get_identification(), packet.ipv4().unwrap().get_identification());
+            assert_eq!(ip.get_total_length() as usize, fragment.len());
+            assert_eq!(ip.get_checksum(), ipv4::checksum(&ip));
+            carried.extend_from_slice(ip.payload());
+        }
+        assert_eq!(carried, packet.payload());
+    }
+
+    #[test]
+    fn ipv6_fragments_share_an_identification() {
+        let hopopt = IpNextHeaderProtocols::Hopopt.0;
+        let data = with_extension(&v6_segment(&[7; 50]), hopopt, &[1, 4, 0, 0, 0, 0]);
+        let packet = IpPacket::new(PacketBuffer::from(data)).unwrap();
+        let fragments = fragment_packet(&packet, &IpFragmentation { fragment_size: 32 });
+        // 70 bytes after the headers
+        assert_eq!(fragments.len(), 3);
+        // the hop-by-hop header stays in front and leads to the fragment header
+        assert!(fragments.iter().all(|f| f[6] == hopopt && f[IPV6_HEADER_LEN] == IpNextHeaderProtocols::Ipv6Frag.0));
+
+        let parsed = ipv6_fragments(&fragments);
+        let offsets: Vec<(usize, bool)> = parsed.iter().map(|(offset, more, _, _)| (*offset, *more)).collect();
+        assert_eq!(offsets, vec![(0, true), (32, true), (64, false)]);
+        assert!(parsed.iter().all(|(_, _, id, _)| *id == parsed[0].2));
+        let carried: Vec<u8> = parsed.into_iter().flat_map(|(_, _, _, data)| data).collect();
+        assert_eq!(carried, packet.payload());
+    }
+
+    #[test]
+    fn first_ipv6_fragment_holds_the_tcp_header() {
+        let packet = IpPacket::new(PacketBuffer::from(v6_segment(&[7; 40]))).unwrap();
+        let parsed = ipv6_fragments(&fragment_packet(&packet, &IpFragmentation { fragment_size: 8 }));
+        assert_eq!(parsed[0].3.len(), 24);
+        assert_eq!(parsed[1].0, 24);
+        assert!(parsed[1..].iter().all(|(_, _, _, data)| data.len() <= 8));
+
+        // IPv4 fragments are cut where they are asked to be
+        let packet = segment(1000, TcpFlags::ACK, &[7; 40]);
+        let first = fragment_packet(&packet, &IpFragmentation { fragment_size: 8 }).remove(0);
+        assert_eq!(first.len(), 20 + 8);
+    }
+
+    #[test]
+    fn cuts() {
+        assert_eq!(fixed_cuts(10, 4), vec![4, 8]);
+        assert_eq!(fixed_cuts(8, 4), vec![4]);
+        assert!(fixed_cuts(3, 4).is_empty());
+        for cut in random_cuts(100, 3, 5).windows(2) {
+            assert!((3..=5).contains(&(cut[1] - cut[0])));
+        }
+    }
+}
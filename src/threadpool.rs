@@ -1,12 +1,15 @@
 use std::{
-    sync::{mpsc, Arc, Mutex},
+    collections::{hash_map::DefaultHasher, VecDeque},
+    hash::{Hash, Hasher},
+    sync::{Arc, Condvar, Mutex},
+    sync::atomic::{AtomicU64, Ordering},
     thread,
 };
 
+use crate::configfile::OverflowPolicy;
+
 pub struct ThreadPool {
     workers: Vec<Worker>,
-    class_size: usize,
-    assignments: Vec<usize>
 }
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
@@ -14,53 +17,65 @@ type Job = Box<dyn FnOnce() + Send + 'static>;
 impl ThreadPool {
     /// Create a new ThreadPool.
     ///
-    /// The size is the number of threads in the pool.
+    /// The size is the number of threads in the pool, each with a queue of `queue_depth` jobs
+    /// and `policy` deciding what happens to a job that finds it full.
     ///
     /// # Panics
     ///
-    /// The `new` function will panic if the size is zero.
-    pub fn new(pool_size: usize, class_size: usize) -> ThreadPool {
-        assert!(pool_size > 0 && class_size > 0);
-
+    /// The `new` function will panic if the size or the depth is zero.
+    pub fn new(pool_size: usize, queue_depth: usize, policy: OverflowPolicy) -> ThreadPool {
+        assert!(pool_size > 0);
+        assert!(queue_depth > 0);
 
         let mut workers = Vec::with_capacity(pool_size);
 
         for id in 0..pool_size {
-            let (sender, receiver) = mpsc::channel();
-            workers.push(Worker::new(id, sender, receiver));
-        }
-
-        let mut assignments = vec![0usize; class_size];
-        let mut j: usize = 0;
-        for i in 0..class_size {
-            assignments[i] = j;
-            j += 1;
-            j %= pool_size;
+            workers.push(Worker::new(id, Arc::new(JobQueue::new(queue_depth, policy))));
         }
 
         ThreadPool {
             workers,
-            class_size,
-            assignments,
         }
     }
 
+    /// Runs `f` on the worker of class `i`, the classes are spread over the workers round-robin
+    /// so the jobs of a class run in order. Classes can come and go, there's no fixed number of them.
     pub fn schedule<F>(&self, f: F, i: usize)
     where
         F: FnOnce() + Send + 'static,
     {
-        let index = self.assignments[i];
+        let index = i % self.workers.len();
         self.workers[index].start(f);
     }
+
+    /// Runs `f` on the worker picked by hashing `key`, the jobs with the same key run in order
+    /// and the keys are spread evenly over the workers.
+    pub fn schedule_by<F, K>(&self, f: F, key: &K)
+    where
+        F: FnOnce() + Send + 'static,
+        K: Hash,
+    {
+        self.schedule(f, class_of(key));
+    }
+
+    /// Number of jobs dropped so far because the queue of their worker was full.
+    pub fn dropped(&self) -> u64 {
+        self.workers.iter().map(|worker| worker.queue.dropped.load(Ordering::Relaxed)).sum()
+    }
+}
+
+/// The class `schedule_by` runs the jobs with `key` in, to pick it before the key is gone.
+pub fn class_of<K: Hash>(key: &K) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish() as usize
 }
 
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        //drop(self.sender.take());
-
         for worker in &mut self.workers {
-            println!("Shutting down worker {}", worker.id);
-            drop(worker.sender.take());
+            println!("Shutting down worker {} ({} jobs dropped)", worker.id, worker.queue.dropped.load(Ordering::Relaxed));
+            worker.queue.close();
 
             if let Some(thread) = worker.thread.take() {
                 thread.join().unwrap();
@@ -71,24 +86,83 @@ impl Drop for ThreadPool {
     }
 }
 
+struct QueueState {
+    jobs: VecDeque<Job>,
+    /// no more jobs are coming, the worker stops once the queue runs dry
+    closed: bool,
+}
+
+/// The bounded queue of jobs of a worker.
+struct JobQueue {
+    state: Mutex<QueueState>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    depth: usize,
+    policy: OverflowPolicy,
+    dropped: AtomicU64,
+}
+
+impl JobQueue {
+    fn new(depth: usize, policy: OverflowPolicy) -> JobQueue {
+        JobQueue {
+            state: Mutex::new(QueueState { jobs: VecDeque::with_capacity(depth), closed: false }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            depth,
+            policy,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    fn push(&self, job: Job) {
+        let mut state = self.state.lock().unwrap();
+        if state.jobs.len() >= self.depth {
+            match self.policy {
+                OverflowPolicy::DropNewest => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    return;
+                },
+                OverflowPolicy::DropOldest => {
+                    state.jobs.pop_front();
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                },
+                OverflowPolicy::Block => {
+                    state = self.not_full.wait_while(state, |s| s.jobs.len() >= self.depth && !s.closed).unwrap();
+                },
+            }
+        }
+        state.jobs.push_back(job);
+        self.not_empty.notify_one();
+    }
+
+    /// Waits for the next job, `None` once the queue is closed and empty.
+    fn pop(&self) -> Option<Job> {
+        let mut state = self.not_empty.wait_while(self.state.lock().unwrap(), |s| s.jobs.is_empty() && !s.closed).unwrap();
+        let job = state.jobs.pop_front();
+        self.not_full.notify_one();
+        job
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+    }
+}
+
 struct Worker {
     id: usize,
     thread: Option<thread::JoinHandle<()>>,
-    sender: Option<mpsc::Sender<Job>>,
+    queue: Arc<JobQueue>,
 }
 
 impl Worker {
-    fn new(id: usize, sender: mpsc::Sender<Job>, receiver: mpsc::Receiver<Job>) -> Worker {
+    fn new(id: usize, queue: Arc<JobQueue>) -> Worker {
+        let jobs = Arc::clone(&queue);
         let thread = thread::spawn(move || loop {
-            let message = receiver.recv();
-
-            match message {
-                Ok(job) => {
-                    println!("Worker {id} got a job; executing.");
-
-                    job();
-                }
-                Err(_) => {
+            match jobs.pop() {
+                Some(job) => job(),
+                None => {
                     println!("Worker {id} disconnected; shutting down.");
                     break;
                 }
@@ -98,7 +172,7 @@ impl Worker {
         Worker {
             id,
             thread: Some(thread),
-            sender: Some(sender),
+            queue,
         }
     }
 
@@ -108,12 +182,115 @@ impl Worker {
     {
         let job = Box::new(f);
 
-        self.sender.as_ref().unwrap().send(job).unwrap();
+        self.queue.push(job);
     }
 }
 
 impl Drop for Worker {
     fn drop(&mut self){
-        drop(self.sender.take());
+        self.queue.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    /// A job that records `n` in `ran` when it runs.
+    fn job(n: usize, ran: &Arc<Mutex<Vec<usize>>>) -> Job {
+        let ran = Arc::clone(ran);
+        Box::new(move || ran.lock().unwrap().push(n))
+    }
+
+    /// Runs what's left in `queue`, in order.
+    fn drain(queue: &JobQueue) {
+        queue.close();
+        while let Some(job) = queue.pop() {
+            job();
+        }
+    }
+
+    #[test]
+    fn drop_newest_keeps_the_waiting_job() {
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        let queue = JobQueue::new(1, OverflowPolicy::DropNewest);
+        for n in 0..3 {
+            queue.push(job(n, &ran));
+        }
+        assert_eq!(queue.dropped.load(Ordering::Relaxed), 2);
+        drain(&queue);
+        assert_eq!(*ran.lock().unwrap(), vec![0]);
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_last_job() {
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        let queue = JobQueue::new(1, OverflowPolicy::DropOldest);
+        for n in 0..3 {
+            queue.push(job(n, &ran));
+        }
+        assert_eq!(queue.dropped.load(Ordering::Relaxed), 2);
+        drain(&queue);
+        assert_eq!(*ran.lock().unwrap(), vec![2]);
+    }
+
+    #[test]
+    fn block_waits_for_room() {
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        let queue = Arc::new(JobQueue::new(1, OverflowPolicy::Block));
+        queue.push(job(0, &ran));
+
+        let (done, pushed) = mpsc::channel();
+        let pusher = {
+            let (queue, ran) = (Arc::clone(&queue), Arc::clone(&ran));
+            thread::spawn(move || {
+                queue.push(job(1, &ran));
+                done.send(()).unwrap();
+            })
+        };
+        assert!(pushed.recv_timeout(Duration::from_millis(100)).is_err());
+
+        queue.pop().unwrap()();
+        pushed.recv_timeout(Duration::from_secs(5)).unwrap();
+        pusher.join().unwrap();
+        assert_eq!(queue.dropped.load(Ordering::Relaxed), 0);
+        drain(&queue);
+        assert_eq!(*ran.lock().unwrap(), vec![0, 1]);
+    }
+
+    #[test]
+    fn close_wakes_a_blocked_pusher() {
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        let queue = Arc::new(JobQueue::new(1, OverflowPolicy::Block));
+        queue.push(job(0, &ran));
+
+        let (done, pushed) = mpsc::channel();
+        let pusher = {
+            let (queue, ran) = (Arc::clone(&queue), Arc::clone(&ran));
+            thread::spawn(move || {
+                queue.push(job(1, &ran));
+                done.send(()).unwrap();
+            })
+        };
+        assert!(pushed.recv_timeout(Duration::from_millis(100)).is_err());
+        queue.close();
+        pushed.recv_timeout(Duration::from_secs(5)).unwrap();
+        pusher.join().unwrap();
+    }
+
+    #[test]
+    fn close_wakes_a_waiting_worker() {
+        let queue = Arc::new(JobQueue::new(1, OverflowPolicy::Block));
+        let (done, popped) = mpsc::channel();
+        let worker = {
+            let queue = Arc::clone(&queue);
+            thread::spawn(move || done.send(queue.pop().is_none()).unwrap())
+        };
+        assert!(popped.recv_timeout(Duration::from_millis(100)).is_err());
+        queue.close();
+        assert!(popped.recv_timeout(Duration::from_secs(5)).unwrap());
+        worker.join().unwrap();
     }
 }
@@ -0,0 +1,152 @@
+
+use std::ops::Range;
+
+pub const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+pub const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+pub const EXTENSION_SERVER_NAME: u16 = 0x0000;
+
+pub const RECORD_HEADER_LEN: usize = 5;
+
+/// Checks whether a tcp payload starts with a TLS handshake record carrying a ClientHello.
+///
+/// Only the record and handshake headers are looked at, the ClientHello itself may
+/// continue in later segments.
+pub fn is_client_hello(payload: &[u8]) -> bool {
+    if payload.len() < RECORD_HEADER_LEN + 1 {
+        return false;
+    }
+
+    // record header: content type (1), legacy version (2), length (2)
+    payload[0] == CONTENT_TYPE_HANDSHAKE
+        && payload[1] == 0x03
+        && payload[2] <= 0x04
+        && payload[RECORD_HEADER_LEN] == HANDSHAKE_CLIENT_HELLO
+}
+
+fn read_u16(data: &[u8], pos: usize) -> Option<usize> {
+    Some(u16::from_be_bytes([*data.get(pos)?, *data.get(pos + 1)?]) as usize)
+}
+
+/// The parts of a ClientHello that matter for placing cuts.
+#[derive(Debug, Clone)]
+pub struct ClientHello {
+    /// offset of the server_name extension header
+    pub sni_extension: Option<usize>,
+    /// where the SNI hostname lies
+    pub hostname: Option<Range<usize>>,
+}
+
+impl ClientHello {
+    /// Parses the ClientHello at the start of a tcp payload.
+    ///
+    /// A ClientHello often doesn't fit into a single segment, so parsing stops silently at the
+    /// end of `payload` and only returns `None` if it isn't a ClientHello at all.
+    pub fn parse(payload: &[u8]) -> Option<ClientHello> {
+        if !is_client_hello(payload) {
+            return None;
+        }
+
+        let mut hello = ClientHello {
+            sni_extension: None,
+            hostname: None,
+        };
+
+        // handshake header (4), client version (2), random (32)
+        let mut pos = RECORD_HEADER_LEN + 4 + 2 + 32;
+        let extensions_end = match skip_to_extensions(payload, &mut pos) {
+            Some(s) => s.min(payload.len()),
+            None => { return Some(hello); }
+        };
+
+        while pos + 4 <= extensions_end {
+            let ext_type = read_u16(payload, pos).unwrap() as u16;
+            let ext_len = read_u16(payload, pos + 2).unwrap();
+            let data = pos + 4..pos + 4 + ext_len;
+            if data.end > payload.len() {
+                break;
+            }
+
+            if ext_type == EXTENSION_SERVER_NAME {
+                hello.sni_extension = Some(pos);
+                // server name list length (2), name type (1), name length (2)
+                if let Some(name_len) = read_u16(payload, data.start + 3) {
+                    let start = data.start + 5;
+                    if start + name_len <= data.end {
+                        hello.hostname = Some(start..start + name_len);
+                    }
+                }
+            }
+
+            pos = data.end;
+        }
+
+        Some(hello)
+    }
+}
+
+/// Moves `pos` past the session id, cipher suites and compression methods to the first
+/// extension and returns where the extensions end.
+fn skip_to_extensions(payload: &[u8], pos: &mut usize) -> Option<usize> {
+    // session id
+    *pos += 1 + *payload.get(*pos)? as usize;
+    // cipher suites
+    *pos += 2 + read_u16(payload, *pos)?;
+    // compression methods
+    *pos += 1 + *payload.get(*pos)? as usize;
+
+    let extensions_end = *pos + 2 + read_u16(payload, *pos)?;
+    *pos += 2;
+    Some(extensions_end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench::client_hello;
+
+    #[test]
+    fn finds_the_hostname() {
+        let payload = client_hello("www.example.com");
+        let hello = ClientHello::parse(&payload).unwrap();
+        assert_eq!(&payload[hello.hostname.unwrap()], b"www.example.com");
+        let ext = hello.sni_extension.unwrap();
+        assert_eq!(read_u16(&payload, ext), Some(EXTENSION_SERVER_NAME as usize));
+    }
+
+    #[test]
+    fn rejects_other_records() {
+        let mut payload = client_hello("example.com");
+        assert!(is_client_hello(&payload));
+        payload[0] = 0x17;
+        assert!(!is_client_hello(&payload));
+        assert!(ClientHello::parse(&payload).is_none());
+        assert!(!is_client_hello(&[CONTENT_TYPE_HANDSHAKE, 0x03, 0x01, 0, 10]));
+    }
+
+    #[test]
+    fn parses_every_truncation() {
+        let payload = client_hello("example.com");
+        let full = ClientHello::parse(&payload).unwrap().hostname.unwrap();
+        for len in 0..payload.len() {
+            let hello = ClientHello::parse(&payload[..len]);
+            if len <= RECORD_HEADER_LEN {
+                assert!(hello.is_none(), "{} bytes", len);
+                continue;
+            }
+            // the hostname is only reported once all of it is there
+            match hello.unwrap().hostname {
+                Some(hostname) => assert!(hostname == full && hostname.end <= len, "{} bytes", len),
+                None => assert!(full.end > len, "{} bytes", len),
+            }
+        }
+    }
+
+    #[test]
+    fn survives_bogus_lengths() {
+        let mut payload = client_hello("example.com");
+        // a session id running past the end of the payload
+        payload[RECORD_HEADER_LEN + 4 + 2 + 32] = 0xff;
+        let hello = ClientHello::parse(&payload).unwrap();
+        assert!(hello.hostname.is_none());
+    }
+}